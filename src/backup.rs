@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Time handling
+use time::OffsetDateTime;
+
+use crate::git::{GitError, GitWrapper};
+use crate::layout::hashed_slug;
+
+// Bare repository inside the mirror directory keeping the overwritten refs of all destinations.
+// The working repositories can't keep them, fetching from the origin prunes all other refs.
+pub const BACKUP_REPO: &str = "git-mirror-backup.git";
+
+// Namespace of the backup refs
+const BACKUP_NAMESPACE: &str = "refs/git-mirror/backup";
+
+/// A ref of a destination saved in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupRef {
+    /// Name of the ref on the destination
+    pub name: String,
+    /// Name of the ref in the backup repository
+    pub backup: String,
+    pub id: String,
+}
+
+/// Name of a backup taken at `t`, e.g. `20261015T101500Z`
+pub fn timestamp(t: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        t.year(),
+        u8::from(t.month()),
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+/// Name of the backup ref of `name` on `destination` in the backup taken at `timestamp`
+fn backup_ref(timestamp: &str, destination: &str, name: &str) -> String {
+    format!(
+        "{BACKUP_NAMESPACE}/{timestamp}/{}/{}",
+        hashed_slug(destination),
+        name.trim_start_matches("refs/")
+    )
+}
+
+/// Save the current tips of the refs `names` on `destination` in the backup `timestamp`
+/// of the backup repository
+pub fn backup(
+    git: &dyn GitWrapper,
+    backup_repo: &Path,
+    destination: &str,
+    names: &[String],
+    timestamp: &str,
+) -> Result<(), GitError> {
+    let specs: Vec<String> = names
+        .iter()
+        .map(|n| format!("+{n}:{}", backup_ref(timestamp, destination, n)))
+        .collect();
+    git.git_fetch(backup_repo, destination, &specs)
+}
+
+/// Push the refs `names` of `destination` saved in the backup `timestamp` to `remote`
+pub fn push(
+    git: &dyn GitWrapper,
+    backup_repo: &Path,
+    destination: &str,
+    names: &[String],
+    timestamp: &str,
+    remote: &str,
+) -> Result<(), GitError> {
+    let specs = names
+        .iter()
+        .map(|n| backup_ref(timestamp, destination, n))
+        .map(|b| format!("{b}:{b}"))
+        .collect();
+    git.git_push_mirror(remote, backup_repo, &Some(specs), false)
+}
+
+/// The backups of `destination` in the backup repository by timestamp
+pub fn list(
+    git: &dyn GitWrapper,
+    backup_repo: &Path,
+    destination: &str,
+) -> Result<BTreeMap<String, Vec<BackupRef>>, GitError> {
+    let mut backups: BTreeMap<String, Vec<BackupRef>> = BTreeMap::new();
+    if !backup_repo.is_dir() {
+        return Ok(backups);
+    }
+
+    let key = hashed_slug(destination);
+    for (backup, id) in git.git_ls_remote(&backup_repo.to_string_lossy())? {
+        let parsed = backup
+            .strip_prefix(BACKUP_NAMESPACE)
+            .and_then(|r| r.strip_prefix('/'))
+            .and_then(|r| r.split_once('/'))
+            .and_then(|(timestamp, r)| {
+                let name = r.strip_prefix(key.as_str())?.strip_prefix('/')?;
+                Some((timestamp.to_string(), format!("refs/{name}")))
+            });
+        if let Some((timestamp, name)) = parsed {
+            backups
+                .entry(timestamp)
+                .or_default()
+                .push(BackupRef { name, backup, id });
+        }
+    }
+    Ok(backups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_ref, timestamp};
+    use time::OffsetDateTime;
+
+    #[test]
+    fn name_backups() {
+        let t = timestamp(OffsetDateTime::from_unix_timestamp(1767323045).unwrap());
+        assert_eq!(t, "20260102T030405Z");
+        let backup = backup_ref(&t, "git@gitlab.com:mirror/a.git", "refs/tags/v1.0");
+        assert!(backup
+            .starts_with("refs/git-mirror/backup/20260102T030405Z/git-gitlab-com-mirror-a-git-"));
+        assert!(backup.ends_with("/tags/v1.0"));
+
+        // Collide when slugified
+        assert_ne!(
+            backup_ref(&t, "git@gitlab.com:a/b-c.git", "refs/tags/v1.0"),
+            backup_ref(&t, "git@gitlab.com:a-b/c.git", "refs/tags/v1.0")
+        );
+    }
+}
@@ -12,6 +12,8 @@ pub enum GitMirrorError {
     MirrorError(#[from] MirrorError),
     #[error("{0} sync tasks failed")]
     SyncError(usize),
+    #[error("Refusing destructive push: {0}")]
+    DestructiveChange(String),
 }
 
 impl From<GitMirrorError> for i32 {
@@ -21,6 +23,7 @@ impl From<GitMirrorError> for i32 {
             GitMirrorError::GenericError(_) => 2,
             GitMirrorError::GitError(_) => 3,
             GitMirrorError::MirrorError(_) => 4,
+            GitMirrorError::DestructiveChange(_) => 5,
         }
     }
 }
@@ -4,11 +4,24 @@
  * SPDX-License-Identifier:     MIT
  */
 
+use std::fmt;
+use std::io::Read;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use log::debug;
+use log::{debug, warn};
+
+#[cfg(feature = "gitoxide")]
+mod gitoxide;
+#[cfg(feature = "gitoxide")]
+pub use self::gitoxide::Gitoxide;
+#[cfg(feature = "libgit2")]
+mod libgit2;
+#[cfg(feature = "libgit2")]
+pub use self::libgit2::Libgit2;
 
 /// An error occuring during git command execution
 #[derive(Debug, Error)]
@@ -21,12 +34,154 @@ pub enum GitError {
         stderr: String,
         cmd: Command,
     },
+    #[error("{operation} aborted after timeout of {timeout:?}")]
+    Timeout {
+        operation: String,
+        timeout: Duration,
+    },
+    #[cfg(feature = "gitoxide")]
+    #[error("{operation} failed with error: {err:#}")]
+    Gitoxide { operation: String, err: gix::Error },
+    #[cfg(feature = "libgit2")]
+    #[error("{operation} failed with error: {err}")]
+    Libgit2 { operation: String, err: git2::Error },
+}
+
+/// Cause of a failed git command, derived from its error output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The connection timed out, was reset or dropped
+    NetworkTimeout,
+    /// The host name couldn't be resolved
+    DnsFailure,
+    /// The credentials were missing or rejected
+    AuthFailure,
+    /// The repository doesn't exist or isn't visible
+    MissingRepository,
+    /// The destination rejected the push to a protected branch
+    ProtectedBranch,
+    /// The destination rejected a non fast-forward update
+    NonFastForward,
+    /// Fetching or pushing LFS objects failed
+    LfsFailure,
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify the stderr of a failed git command
+    pub fn classify(stderr: &str) -> ErrorKind {
+        let stderr = stderr.to_lowercase();
+        let any = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+
+        // The most specific causes first, many failures end in a generic disconnect
+        if any(&[
+            "could not resolve host",
+            "could not resolve hostname",
+            "name or service not known",
+            "temporary failure in name resolution",
+            "nodename nor servname provided",
+        ]) {
+            ErrorKind::DnsFailure
+        } else if any(&[
+            "authentication failed",
+            "permission denied",
+            "could not read username",
+            "could not read password",
+            "invalid username or password",
+            "access denied",
+            "returned error: 401",
+            "returned error: 403",
+        ]) {
+            ErrorKind::AuthFailure
+        } else if any(&[
+            "repository not found",
+            "does not appear to be a git repository",
+            "does not exist",
+            "could not be found",
+            "returned error: 404",
+        ]) {
+            ErrorKind::MissingRepository
+        } else if any(&[
+            "protected branch",
+            "protected ref",
+            "pre-receive hook declined",
+        ]) {
+            ErrorKind::ProtectedBranch
+        } else if any(&["non-fast-forward", "fetch first", "updates were rejected"]) {
+            ErrorKind::NonFastForward
+        } else if any(&[
+            "git-lfs",
+            "git lfs",
+            "lfs:",
+            "'lfs' is not a git command",
+            "batch response",
+            "smudge error",
+        ]) {
+            ErrorKind::LfsFailure
+        } else if any(&[
+            "timed out",
+            "connection reset",
+            "connection refused",
+            "connection closed",
+            "failed to connect",
+            "could not connect",
+            "unexpected disconnect",
+            "remote end hung up unexpectedly",
+            "early eof",
+            "rpc failed",
+            "broken pipe",
+            "returned error: 502",
+            "returned error: 503",
+            "returned error: 504",
+        ]) {
+            ErrorKind::NetworkTimeout
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    /// Whether retrying the command might succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ErrorKind::NetworkTimeout | ErrorKind::DnsFailure)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            ErrorKind::NetworkTimeout => "network timeout",
+            ErrorKind::DnsFailure => "dns failure",
+            ErrorKind::AuthFailure => "auth failure",
+            ErrorKind::MissingRepository => "missing repository",
+            ErrorKind::ProtectedBranch => "protected branch",
+            ErrorKind::NonFastForward => "non-fast-forward",
+            ErrorKind::LfsFailure => "lfs failure",
+            ErrorKind::Other => "sync error",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+impl GitError {
+    /// Classify the cause of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            GitError::CommandError { .. } => ErrorKind::Other,
+            // Usually caused by an unresponsive remote
+            GitError::Timeout { .. } => ErrorKind::NetworkTimeout,
+            GitError::GitCommandError { stderr, .. } => ErrorKind::classify(stderr),
+            #[cfg(feature = "gitoxide")]
+            GitError::Gitoxide { err, .. } => gitoxide::classify(err),
+            #[cfg(feature = "libgit2")]
+            GitError::Libgit2 { err, .. } => libgit2::classify(err),
+        }
+    }
 }
 
 /// Common interface to different git backends
 /// - [x] git command line
-/// - [ ] libgit2
-/// - [ ] gitoxide
+/// - [x] libgit2 (feature `libgit2`)
+/// - [x] gitoxide (feature `gitoxide`)
 ///
 pub trait GitWrapper {
     /// Get the git version
@@ -41,31 +196,105 @@ pub trait GitWrapper {
         refspec: &Option<Vec<String>>,
         lfs: bool,
     ) -> Result<(), GitError>;
+    /// List the refs of a remote repository as `(name, object id)` pairs
+    fn git_ls_remote(&self, url: &str) -> Result<Vec<(String, String)>, GitError>;
+    /// Get the branch `HEAD` of a remote repository points to, e.g. `refs/heads/main`
+    fn git_default_branch(&self, url: &str) -> Result<Option<String>, GitError>;
+    /// Fetch `refspecs` from `url` into the bare repository at `repo_dir`, creating it if needed
+    fn git_fetch(&self, repo_dir: &Path, url: &str, refspecs: &[String]) -> Result<(), GitError>;
+    /// Check if commit `descendant` of the repository contains commit `ancestor` in its history,
+    /// an `ancestor` unknown to the repository isn't
+    fn git_is_ancestor(
+        &self,
+        repo_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitError>;
+}
+
+/// Longest time each kind of git operation may take, `None` waits forever
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    pub clone: Option<Duration>,
+    /// Used for fetching and listing remote refs
+    pub update: Option<Duration>,
+    pub push: Option<Duration>,
+    pub lfs: Option<Duration>,
 }
 
+// Interval to check if a command with a timeout finished
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Git command line wrapper
 pub struct Git {
     executable: String,
     lfs_enabled: bool,
+    timeouts: Timeouts,
 }
 
 impl Git {
-    pub fn new(executable: String, lfs_enabled: bool) -> Git {
+    pub fn new(executable: String, lfs_enabled: bool, timeouts: Timeouts) -> Git {
         Git {
             executable,
             lfs_enabled,
+            timeouts,
         }
     }
 
+    /// Fetch the LFS objects of all refs in the repository
+    pub fn git_lfs_fetch(&self, repo_dir: &Path) -> Result<(), GitError> {
+        let mut lfs_fetch_cmd = self.git_base_cmd();
+        lfs_fetch_cmd.args(["lfs", "fetch"]).current_dir(repo_dir);
+
+        self.run_cmd(lfs_fetch_cmd, self.timeouts.lfs)
+    }
+
+    /// Get the URL of the origin remote of a repository
+    pub fn git_remote_url(&self, repo_dir: &Path) -> Result<String, GitError> {
+        let mut get_url_cmd = self.git_base_cmd();
+        get_url_cmd
+            .args(["config", "--get", "remote.origin.url"])
+            .current_dir(repo_dir);
+
+        Ok(self
+            .run_cmd_output(get_url_cmd, None)?
+            .trim_end()
+            .to_string())
+    }
+
     fn git_base_cmd(&self) -> Command {
         let mut git = Command::new(self.executable.clone());
         git.env("GIT_TERMINAL_PROMPT", "0");
         git
     }
 
-    fn run_cmd(&self, mut cmd: Command) -> Result<(), GitError> {
+    fn run_cmd(&self, cmd: Command, timeout: Option<Duration>) -> Result<(), GitError> {
+        self.run_cmd_output(cmd, timeout).map(|_| ())
+    }
+
+    /// Run a command and return its stdout.
+    ///
+    /// If the command doesn't finish within `timeout` it is killed together with its children.
+    fn run_cmd_output(
+        &self,
+        mut cmd: Command,
+        timeout: Option<Duration>,
+    ) -> Result<String, GitError> {
         debug!("Run command: {:?}", cmd);
-        match cmd.output() {
+        let output = match timeout {
+            Some(timeout) => match output_with_timeout(&mut cmd, timeout) {
+                Ok(Some(o)) => Ok(o),
+                Ok(None) => {
+                    return Err(GitError::Timeout {
+                        operation: format!("Command {cmd:?}"),
+                        timeout,
+                    })
+                }
+                Err(e) => Err(e),
+            },
+            None => cmd.output(),
+        };
+        match output {
             Ok(o) => {
                 let stdout = String::from_utf8_lossy(&o.stdout).to_string();
                 if !stdout.is_empty() {
@@ -76,7 +305,7 @@ impl Git {
                     debug!("Stderr: {}", stderr);
                 }
                 if o.status.success() {
-                    Ok(())
+                    Ok(stdout)
                 } else {
                     Err(GitError::GitCommandError {
                         cmd,
@@ -90,12 +319,71 @@ impl Git {
     }
 }
 
+/// Run a command and collect its output, like `Command::output`.
+///
+/// Returns `None` if the command was killed because it ran longer than `timeout`.
+fn output_with_timeout(cmd: &mut Command, timeout: Duration) -> std::io::Result<Option<Output>> {
+    // Run the command in its own process group, so helpers like ssh can be killed as well
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the pipes in the background so the command doesn't block on a full pipe
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let collect = |r: Option<thread::JoinHandle<Vec<u8>>>| {
+                r.and_then(|r| r.join().ok()).unwrap_or_default()
+            };
+            return Ok(Some(Output {
+                status,
+                stdout: collect(stdout),
+                stderr: collect(stderr),
+            }));
+        }
+        if Instant::now() >= deadline {
+            warn!("Killing {:?} after timeout of {:?}", cmd, timeout);
+            kill(&mut child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// Kill a command started by `output_with_timeout` together with its children
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: kill has no memory safety requirements, the process group was created for the child
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = child.kill();
+}
+
 impl GitWrapper for Git {
     fn git_version(&self) -> Result<(), GitError> {
         let mut cmd = self.git_base_cmd();
         cmd.arg("--version");
 
-        self.run_cmd(cmd)
+        self.run_cmd(cmd, None)
     }
 
     fn git_lfs_version(&self) -> Result<(), GitError> {
@@ -103,7 +391,7 @@ impl GitWrapper for Git {
         cmd.arg("lfs");
         cmd.arg("version");
 
-        self.run_cmd(cmd)
+        self.run_cmd(cmd, None)
     }
 
     fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
@@ -113,13 +401,10 @@ impl GitWrapper for Git {
             .arg(origin)
             .arg(repo_dir);
 
-        self.run_cmd(clone_cmd)?;
+        self.run_cmd(clone_cmd, self.timeouts.clone)?;
 
         if self.lfs_enabled && lfs {
-            let mut lfs_fetch_cmd = self.git_base_cmd();
-            lfs_fetch_cmd.args(["lfs", "fetch"]).current_dir(repo_dir);
-
-            self.run_cmd(lfs_fetch_cmd)
+            self.git_lfs_fetch(repo_dir)
         } else {
             Ok(())
         }
@@ -132,20 +417,17 @@ impl GitWrapper for Git {
             .args(["remote", "set-url", "origin"])
             .arg(origin);
 
-        self.run_cmd(set_url_cmd)?;
+        self.run_cmd(set_url_cmd, None)?;
 
         let mut remote_update_cmd = self.git_base_cmd();
         remote_update_cmd
             .current_dir(repo_dir)
             .args(["remote", "update", "--prune"]);
 
-        self.run_cmd(remote_update_cmd)?;
+        self.run_cmd(remote_update_cmd, self.timeouts.update)?;
 
         if self.lfs_enabled && lfs {
-            let mut lfs_fetch_cmd = self.git_base_cmd();
-            lfs_fetch_cmd.args(["lfs", "fetch"]).current_dir(repo_dir);
-
-            self.run_cmd(lfs_fetch_cmd)
+            self.git_lfs_fetch(repo_dir)
         } else {
             Ok(())
         }
@@ -163,7 +445,7 @@ impl GitWrapper for Git {
             lfs_install_cmd
                 .args(["lfs", "install"])
                 .current_dir(repo_dir);
-            self.run_cmd(lfs_install_cmd)?;
+            self.run_cmd(lfs_install_cmd, None)?;
         }
 
         let mut push_cmd = self.git_base_cmd();
@@ -177,6 +459,139 @@ impl GitWrapper for Git {
         } else {
             push_cmd.args(["--mirror", dest]);
         }
-        self.run_cmd(push_cmd)
+        self.run_cmd(push_cmd, self.timeouts.push)
+    }
+
+    fn git_ls_remote(&self, url: &str) -> Result<Vec<(String, String)>, GitError> {
+        let mut ls_remote_cmd = self.git_base_cmd();
+        ls_remote_cmd.arg("ls-remote").arg(url);
+
+        let stdout = self.run_cmd_output(ls_remote_cmd, self.timeouts.update)?;
+
+        Ok(stdout
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .map(|(id, name)| (name.to_string(), id.to_string()))
+            .collect())
+    }
+
+    fn git_default_branch(&self, url: &str) -> Result<Option<String>, GitError> {
+        let mut ls_remote_cmd = self.git_base_cmd();
+        ls_remote_cmd
+            .args(["ls-remote", "--symref"])
+            .arg(url)
+            .arg("HEAD");
+
+        let stdout = self.run_cmd_output(ls_remote_cmd, self.timeouts.update)?;
+
+        // The symbolic ref is listed as `ref: refs/heads/main<TAB>HEAD`
+        Ok(stdout.lines().find_map(|l| {
+            let (target, name) = l.strip_prefix("ref: ")?.split_once('\t')?;
+            (name == "HEAD").then(|| target.to_string())
+        }))
+    }
+
+    fn git_fetch(&self, repo_dir: &Path, url: &str, refspecs: &[String]) -> Result<(), GitError> {
+        if !repo_dir.is_dir() {
+            let mut init_cmd = self.git_base_cmd();
+            init_cmd.args(["init", "--bare"]).arg(repo_dir);
+            self.run_cmd(init_cmd, None)?;
+        }
+
+        let mut fetch_cmd = self.git_base_cmd();
+        fetch_cmd
+            .current_dir(repo_dir)
+            .args(["fetch", "--no-tags", "--no-write-fetch-head"])
+            .arg(url)
+            .args(refspecs);
+
+        self.run_cmd(fetch_cmd, self.timeouts.update)
+    }
+
+    fn git_is_ancestor(
+        &self,
+        repo_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitError> {
+        let mut merge_base_cmd = self.git_base_cmd();
+        merge_base_cmd
+            .args(["merge-base", "--is-ancestor", ancestor, descendant])
+            .current_dir(repo_dir);
+
+        match self.run_cmd(merge_base_cmd, None) {
+            Ok(()) => Ok(true),
+            // 1 if it isn't an ancestor, 128 if the commit is unknown
+            Err(GitError::GitCommandError { code: 1 | 128, .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{output_with_timeout, ErrorKind};
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn classify_errors() {
+        for (stderr, kind) in [
+            (
+                "fatal: unable to access 'https://example.org/a.git/': Could not resolve host: example.org",
+                ErrorKind::DnsFailure,
+            ),
+            (
+                "error: RPC failed; curl 56 Recv failure: Connection reset by peer\nfatal: early EOF",
+                ErrorKind::NetworkTimeout,
+            ),
+            (
+                "ssh: connect to host example.org port 22: Connection timed out\nfatal: Could not read from remote repository.",
+                ErrorKind::NetworkTimeout,
+            ),
+            (
+                "git@example.org: Permission denied (publickey).\nfatal: Could not read from remote repository.",
+                ErrorKind::AuthFailure,
+            ),
+            (
+                "remote: Repository not found.\nfatal: repository 'https://github.com/a/b.git/' not found",
+                ErrorKind::MissingRepository,
+            ),
+            (
+                "remote: GitLab: You are not allowed to force push code to a protected branch on this project.\n ! [remote rejected] main -> main (pre-receive hook declined)",
+                ErrorKind::ProtectedBranch,
+            ),
+            (
+                " ! [rejected]        main -> main (non-fast-forward)",
+                ErrorKind::NonFastForward,
+            ),
+            (
+                "batch response: Repository or object not found",
+                ErrorKind::LfsFailure,
+            ),
+            ("fatal: something unexpected", ErrorKind::Other),
+        ] {
+            assert_eq!(ErrorKind::classify(stderr), kind, "{stderr}");
+        }
+        assert!(ErrorKind::NetworkTimeout.is_transient());
+        assert!(!ErrorKind::AuthFailure.is_transient());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_after_timeout() {
+        let start = Instant::now();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 10 & wait"]);
+        let output = output_with_timeout(&mut cmd, Duration::from_millis(200)).unwrap();
+        assert!(output.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo done"]);
+        let output = output_with_timeout(&mut cmd, Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.stdout, b"done\n");
     }
 }
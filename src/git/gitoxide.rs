@@ -0,0 +1,297 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use gix::progress::Discard;
+use gix::refs::transaction::{Change, PreviousValue, RefEdit, RefLog};
+use gix::remote::Direction;
+
+use super::{ErrorKind, Git, GitError, GitWrapper, Timeouts};
+
+// Mirror all refs, like `git clone --mirror`
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+
+/// Ask the remote for all refs, including HEAD.
+///
+/// No prefix can be derived from the mirror refspec, with the default
+/// the remote would only be asked for tags.
+fn all_refs() -> gix::remote::ref_map::Options {
+    gix::remote::ref_map::Options {
+        prefix_from_spec_as_filter_on_remote: false,
+        ..Default::default()
+    }
+}
+
+/// Git backend based on gitoxide.
+///
+/// Cloning, fetching and listing remote refs are done in process.
+/// Pushing, LFS, fetching single refs and getting the default branch of a remote
+/// use the git command line.
+pub struct Gitoxide {
+    cli: Git,
+    lfs_enabled: bool,
+    timeouts: Timeouts,
+    /// Bare repository needed to list the refs of a remote without a local clone
+    scratch_dir: PathBuf,
+}
+
+impl Gitoxide {
+    pub fn new(
+        executable: String,
+        lfs_enabled: bool,
+        timeouts: Timeouts,
+        scratch_dir: PathBuf,
+    ) -> Gitoxide {
+        Gitoxide {
+            cli: Git::new(executable, lfs_enabled, timeouts),
+            lfs_enabled,
+            timeouts,
+            scratch_dir,
+        }
+    }
+
+    fn scratch_repo(&self) -> gix::Result<gix::Repository> {
+        // Another worker might create it at the same time
+        gix::open(&self.scratch_dir)
+            .or_else(|_| gix::init_bare(&self.scratch_dir))
+            .or_else(|_| gix::open(&self.scratch_dir))
+    }
+}
+
+/// Run a gitoxide operation, asking it to stop once `timeout` expired
+fn run<T>(
+    operation: String,
+    timeout: Option<Duration>,
+    f: impl FnOnce(&AtomicBool) -> gix::Result<T>,
+) -> Result<T, GitError> {
+    debug!("Run {} using gitoxide", operation);
+    let interrupt = AtomicBool::new(false);
+    let result = match timeout {
+        Some(timeout) => thread::scope(|s| {
+            let (done, finished) = mpsc::channel::<()>();
+            let (interrupt, operation) = (&interrupt, &operation);
+            s.spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+                    warn!("Interrupting {} after timeout of {:?}", operation, timeout);
+                    interrupt.store(true, Ordering::Relaxed);
+                }
+            });
+            let result = f(interrupt);
+            drop(done);
+            result
+        }),
+        None => f(&interrupt),
+    };
+    result.map_err(|err| match timeout {
+        Some(timeout) if interrupt.load(Ordering::Relaxed) => {
+            GitError::Timeout { operation, timeout }
+        }
+        _ => GitError::Gitoxide { operation, err },
+    })
+}
+
+/// Classify a gitoxide error, using the underlying IO error if there is one
+pub(super) fn classify(err: &gix::Error) -> ErrorKind {
+    use std::io::ErrorKind::*;
+    let io_kind = err
+        .iter_errors()
+        .find_map(|e| e.downcast_ref::<std::io::Error>())
+        .map(|e| e.kind());
+    match io_kind {
+        Some(
+            TimedOut | ConnectionReset | ConnectionAborted | ConnectionRefused | BrokenPipe
+            | UnexpectedEof,
+        ) => ErrorKind::NetworkTimeout,
+        _ => ErrorKind::classify(&format!("{err:#}")),
+    }
+}
+
+/// Delete the refs that no longer exist on the remote, like `git remote update --prune`
+fn prune(repo: &gix::Repository, remote_refs: &[gix::protocol::handshake::Ref]) -> gix::Result<()> {
+    let remote: HashSet<_> = remote_refs.iter().map(|r| r.unpack().0).collect();
+    let mut edits = Vec::new();
+    for r in repo.references()?.all()? {
+        let r = r.map_err(gix::Error::from_error)?;
+        if !remote.contains(r.name().as_bstr()) {
+            debug!("Pruning {}", r.name().as_bstr());
+            edits.push(RefEdit {
+                change: Change::Delete {
+                    expected: PreviousValue::Any,
+                    log: RefLog::AndReference,
+                },
+                name: r.name().to_owned(),
+                deref: false,
+            });
+        }
+    }
+    repo.edit_references(edits)?;
+    Ok(())
+}
+
+/// Point the origin remote to `origin`, like `git remote set-url origin`.
+///
+/// The config is written to `config.lock` first and renamed, like git does.
+fn set_origin_url(repo: &gix::Repository, origin: &str) -> gix::Result<()> {
+    let path = repo.git_dir().join("config");
+    let mut config =
+        gix::config::File::from_path_no_includes(path.clone(), gix::config::Source::Local)?;
+    if config
+        .raw_value("remote.origin.url")
+        .is_ok_and(|url| url == origin.as_bytes())
+    {
+        return Ok(());
+    }
+    config.set_raw_value("remote.origin.url", origin)?;
+    let lock_path = repo.git_dir().join("config.lock");
+    let mut content = Vec::new();
+    config
+        .write_to(&mut content)
+        .and_then(|_| std::fs::write(&lock_path, content))
+        .and_then(|_| std::fs::rename(&lock_path, &path))
+        .map_err(gix::Error::from_error)
+}
+
+impl GitWrapper for Gitoxide {
+    fn git_version(&self) -> Result<(), GitError> {
+        debug!("Using gitoxide");
+        Ok(())
+    }
+
+    fn git_lfs_version(&self) -> Result<(), GitError> {
+        self.cli.git_lfs_version()
+    }
+
+    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
+        run(
+            format!("Clone of {origin}"),
+            self.timeouts.clone,
+            |interrupt| {
+                let mut clone = gix::prepare_clone_bare(origin, repo_dir)?
+                    .with_remote_name("origin")?
+                    .with_fetch_options(all_refs())
+                    .configure_remote(|mut r| {
+                        r.replace_refspecs([MIRROR_REFSPEC], Direction::Fetch)?;
+                        Ok(r)
+                    });
+                clone.fetch_only(Discard, interrupt)?;
+                Ok(())
+            },
+        )?;
+
+        if self.lfs_enabled && lfs {
+            self.cli.git_lfs_fetch(repo_dir)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn git_update_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
+        run(
+            format!("Fetch of {origin}"),
+            self.timeouts.update,
+            |interrupt| {
+                let repo = gix::open(repo_dir)?;
+                set_origin_url(&repo, origin)?;
+                let outcome = repo
+                    .remote_at(origin)?
+                    .with_refspecs([MIRROR_REFSPEC], Direction::Fetch)?
+                    .connect(Direction::Fetch)?
+                    .prepare_fetch(Discard, all_refs())?
+                    .receive(Discard, interrupt)?;
+                prune(&repo, &outcome.ref_map.remote_refs)
+            },
+        )?;
+
+        if self.lfs_enabled && lfs {
+            self.cli.git_lfs_fetch(repo_dir)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn git_push_mirror(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        refspec: &Option<Vec<String>>,
+        lfs: bool,
+    ) -> Result<(), GitError> {
+        self.cli.git_push_mirror(dest, repo_dir, refspec, lfs)
+    }
+
+    fn git_ls_remote(&self, url: &str) -> Result<Vec<(String, String)>, GitError> {
+        let path = Path::new(url);
+        if path.is_dir() {
+            // Local repositories are read directly instead of spawning `git upload-pack`
+            return run(format!("Listing refs of {url}"), None, |_| {
+                let repo = gix::open(path)?;
+                let mut refs = Vec::new();
+                if let Ok(id) = repo.head_id() {
+                    refs.push(("HEAD".to_string(), id.to_string()));
+                }
+                for r in repo.references()?.all()? {
+                    let mut r = r.map_err(gix::Error::from_error)?;
+                    // Annotated tags are listed with the id of the tag object, like `git ls-remote`
+                    let id = match r.try_id() {
+                        Some(id) => id.detach(),
+                        None => r.peel_to_id()?.detach(),
+                    };
+                    refs.push((r.name().as_bstr().to_string(), id.to_string()));
+                }
+                Ok(refs)
+            });
+        }
+
+        run(
+            format!("Listing refs of {url}"),
+            self.timeouts.update,
+            |_| {
+                let repo = self.scratch_repo()?;
+                let (ref_map, _) = repo
+                    .remote_at(url)?
+                    .with_refspecs([MIRROR_REFSPEC], Direction::Fetch)?
+                    .connect(Direction::Fetch)?
+                    .ref_map(Discard, all_refs())?;
+                let mut refs = Vec::new();
+                for r in ref_map.remote_refs.iter() {
+                    let (name, id, peeled) = r.unpack();
+                    if let Some(id) = id {
+                        refs.push((name.to_string(), id.to_string()));
+                    }
+                    if let Some(peeled) = peeled {
+                        refs.push((format!("{name}^{{}}"), peeled.to_string()));
+                    }
+                }
+                Ok(refs)
+            },
+        )
+    }
+
+    fn git_default_branch(&self, url: &str) -> Result<Option<String>, GitError> {
+        self.cli.git_default_branch(url)
+    }
+
+    fn git_fetch(&self, repo_dir: &Path, url: &str, refspecs: &[String]) -> Result<(), GitError> {
+        self.cli.git_fetch(repo_dir, url, refspecs)
+    }
+
+    fn git_is_ancestor(
+        &self,
+        repo_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitError> {
+        self.cli.git_is_ancestor(repo_dir, ancestor, descendant)
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use git2::build::RepoBuilder;
+use git2::{
+    AutotagOption, Config, Cred, CredentialType, Direction, ErrorCode, FetchOptions, FetchPrune,
+    PushOptions, Remote, RemoteCallbacks, RemoteConnection, Repository,
+};
+
+use super::{ErrorKind, Git, GitError, GitWrapper, Timeouts};
+
+// Mirror all refs, like `git clone --mirror`
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+
+// Private keys tried in order if the ssh agent didn't provide an accepted key
+const SSH_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Git backend based on libgit2.
+///
+/// Cloning, fetching, pushing and listing remote refs are done in process.
+/// LFS isn't supported by libgit2 and uses the git command line.
+///
+/// SSH remotes are authenticated using the ssh agent or the default keys in `~/.ssh`,
+/// HTTPS remotes using the git credential helpers or the given token.
+pub struct Libgit2 {
+    cli: Git,
+    lfs_enabled: bool,
+    timeouts: Timeouts,
+    token: Option<String>,
+}
+
+impl Libgit2 {
+    pub fn new(
+        executable: String,
+        lfs_enabled: bool,
+        timeouts: Timeouts,
+        token: Option<String>,
+    ) -> Libgit2 {
+        Libgit2 {
+            cli: Git::new(executable, lfs_enabled, timeouts),
+            lfs_enabled,
+            timeouts,
+            token,
+        }
+    }
+
+    /// Callbacks providing the credentials and aborting the operation after the deadline.
+    ///
+    /// libgit2 can only be interrupted while it reports progress, `timed_out` is set if it was.
+    fn callbacks<'a>(
+        &'a self,
+        deadline: Option<Instant>,
+        timed_out: &'a Cell<bool>,
+    ) -> RemoteCallbacks<'a> {
+        let proceed = move || {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                timed_out.set(true);
+                false
+            } else {
+                true
+            }
+        };
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.transfer_progress(move |_| proceed());
+        callbacks.sideband_progress(move |_| proceed());
+
+        // libgit2 asks again as long as the returned credentials are rejected
+        let mut tried_agent = false;
+        let mut ssh_keys = ssh_keys().into_iter();
+        let mut tried_helper = false;
+        let mut token = self.token.as_deref();
+        callbacks.credentials(move |url, username, allowed| {
+            let user = username.unwrap_or("git");
+            if allowed.contains(CredentialType::USERNAME) {
+                return Cred::username(user);
+            }
+            if allowed.contains(CredentialType::SSH_KEY) {
+                if !tried_agent {
+                    tried_agent = true;
+                    debug!("Trying ssh agent for {}", url);
+                    return Cred::ssh_key_from_agent(user);
+                }
+                if let Some(key) = ssh_keys.next() {
+                    debug!("Trying ssh key {:?} for {}", key, url);
+                    return Cred::ssh_key(user, None, &key, None);
+                }
+            }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                if !tried_helper {
+                    tried_helper = true;
+                    let helper = Config::open_default()
+                        .and_then(|c| Cred::credential_helper(&c, url, username));
+                    if let Ok(cred) = helper {
+                        debug!("Trying credential helper for {}", url);
+                        return Ok(cred);
+                    }
+                }
+                if let Some(t) = token.take() {
+                    debug!("Trying token for {}", url);
+                    // Most providers accept any user name together with an access token
+                    return Cred::userpass_plaintext(username.unwrap_or("oauth2"), t);
+                }
+            }
+            Err(git2::Error::from_str(&format!(
+                "Authentication failed for {url}, no more credentials to try"
+            )))
+        });
+        callbacks
+    }
+
+    /// Force push the given refspecs, failing if the destination rejected any of them
+    fn push(&self, dest: &str, repo_dir: &Path, specs: &[String]) -> Result<(), GitError> {
+        self.run(format!("Push to {dest}"), self.timeouts.push, |callbacks| {
+            let repo = Repository::open_bare(repo_dir)?;
+            let mut remote = repo.remote_anonymous(dest)?;
+
+            // Rejected refs don't fail the push itself
+            let rejected = RefCell::new(Vec::new());
+            let mut callbacks = callbacks();
+            callbacks.push_update_reference(|name, status| {
+                if let Some(status) = status {
+                    rejected.borrow_mut().push(format!("{name} ({status})"));
+                }
+                Ok(())
+            });
+            remote.push(specs, Some(PushOptions::new().remote_callbacks(callbacks)))?;
+
+            let rejected = rejected.into_inner();
+            if rejected.is_empty() {
+                Ok(())
+            } else {
+                Err(git2::Error::from_str(&format!(
+                    "Updates were rejected: {}",
+                    rejected.join(", ")
+                )))
+            }
+        })
+    }
+
+    /// Run a libgit2 operation with callbacks honoring `timeout`
+    fn run<T>(
+        &self,
+        operation: String,
+        timeout: Option<Duration>,
+        f: impl for<'a> FnOnce(&'a dyn Fn() -> RemoteCallbacks<'a>) -> Result<T, git2::Error>,
+    ) -> Result<T, GitError> {
+        debug!("Run {} using libgit2", operation);
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let timed_out = Cell::new(false);
+        f(&|| self.callbacks(deadline, &timed_out)).map_err(|err| match timeout {
+            Some(timeout) if timed_out.get() => {
+                warn!("Interrupted {} after timeout of {:?}", operation, timeout);
+                GitError::Timeout { operation, timeout }
+            }
+            _ => GitError::Libgit2 { operation, err },
+        })
+    }
+}
+
+/// The default private keys of the user that exist
+fn ssh_keys() -> Vec<PathBuf> {
+    let Some(home) = env::var_os("HOME") else {
+        return Vec::new();
+    };
+    let ssh_dir = Path::new(&home).join(".ssh");
+    SSH_KEYS
+        .iter()
+        .map(|k| ssh_dir.join(k))
+        .filter(|k| k.is_file())
+        .collect()
+}
+
+/// Classify a libgit2 error using its code and message
+pub(super) fn classify(err: &git2::Error) -> ErrorKind {
+    let message = err.message().to_lowercase();
+    match err.code() {
+        ErrorCode::Auth => ErrorKind::AuthFailure,
+        ErrorCode::NotFastForward => ErrorKind::NonFastForward,
+        ErrorCode::Timeout => ErrorKind::NetworkTimeout,
+        // HTTP errors are reported by status code only
+        _ if message.contains("status code: 401") || message.contains("status code: 403") => {
+            ErrorKind::AuthFailure
+        }
+        _ if message.contains("status code: 404") => ErrorKind::MissingRepository,
+        _ if message.contains("status code: 5") => ErrorKind::NetworkTimeout,
+        _ if message.contains("failed to resolve address") => ErrorKind::DnsFailure,
+        _ => ErrorKind::classify(&message),
+    }
+}
+
+fn fetch_options(callbacks: RemoteCallbacks<'_>) -> FetchOptions<'_> {
+    let mut options = FetchOptions::new();
+    options
+        .remote_callbacks(callbacks)
+        .prune(FetchPrune::On)
+        .download_tags(AutotagOption::None);
+    options
+}
+
+/// Refs advertised by a connected remote as `(name, object id)` pairs.
+///
+/// `None` if the remote might not advertise any refs, which `Remote::list` can't handle.
+fn list(connection: &RemoteConnection) -> Result<Option<Vec<(String, String)>>, git2::Error> {
+    // Only found if the remote advertised HEAD
+    match connection.default_branch() {
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        result => result?,
+    };
+    Ok(Some(
+        connection
+            .list()?
+            .iter()
+            .map(|h| (h.name().to_string(), h.oid().to_string()))
+            .collect(),
+    ))
+}
+
+impl GitWrapper for Libgit2 {
+    fn git_version(&self) -> Result<(), GitError> {
+        let (major, minor, rev) = git2::Version::get().libgit2_version();
+        debug!("Using libgit2 {}.{}.{}", major, minor, rev);
+        Ok(())
+    }
+
+    fn git_lfs_version(&self) -> Result<(), GitError> {
+        self.cli.git_lfs_version()
+    }
+
+    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
+        self.run(
+            format!("Clone of {origin}"),
+            self.timeouts.clone,
+            |callbacks| {
+                let repo = RepoBuilder::new()
+                    .bare(true)
+                    .remote_create(|repo, name, url| {
+                        repo.remote_with_fetch(name, url, MIRROR_REFSPEC)
+                    })
+                    .fetch_options(fetch_options(callbacks()))
+                    .clone(origin, repo_dir)?;
+                // Same configuration as `git clone --mirror`, so the command line can take over
+                repo.config()?.set_bool("remote.origin.mirror", true)
+            },
+        )?;
+
+        if self.lfs_enabled && lfs {
+            self.cli.git_lfs_fetch(repo_dir)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn git_update_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
+        self.run(
+            format!("Fetch of {origin}"),
+            self.timeouts.update,
+            |callbacks| {
+                let repo = Repository::open_bare(repo_dir)?;
+                repo.remote_set_url("origin", origin)?;
+                let mut remote = repo.find_remote("origin")?;
+                remote.fetch(
+                    &[MIRROR_REFSPEC],
+                    Some(&mut fetch_options(callbacks())),
+                    None,
+                )
+            },
+        )?;
+
+        if self.lfs_enabled && lfs {
+            self.cli.git_lfs_fetch(repo_dir)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn git_push_mirror(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        refspec: &Option<Vec<String>>,
+        lfs: bool,
+    ) -> Result<(), GitError> {
+        if self.lfs_enabled && lfs {
+            // Pushes the LFS objects using the pre-push hook
+            return self.cli.git_push_mirror(dest, repo_dir, refspec, lfs);
+        }
+
+        if let Some(r) = refspec {
+            // Force push like `git push -f`
+            let specs: Vec<String> = r
+                .iter()
+                .map(|s| match s.starts_with('+') {
+                    true => s.clone(),
+                    false => format!("+{s}"),
+                })
+                .collect();
+            return self.push(dest, repo_dir, &specs);
+        }
+
+        // Like `git push --mirror`, update all refs and delete the ones gone locally
+        let local = self.run(format!("Listing refs of {repo_dir:?}"), None, |_| {
+            let repo = Repository::open_bare(repo_dir)?;
+            let mut local = HashSet::new();
+            for r in repo.references()? {
+                if let Some(name) = r?.name().filter(|n| n.starts_with("refs/")) {
+                    local.insert(name.to_string());
+                }
+            }
+            Ok(local)
+        })?;
+        if local.is_empty() {
+            return Ok(());
+        }
+        let specs: Vec<String> = local.iter().map(|n| format!("+{n}:{n}")).collect();
+        self.push(dest, repo_dir, &specs)?;
+
+        let deleted: Vec<String> = self
+            .git_ls_remote(dest)?
+            .into_iter()
+            .filter(|(n, _)| n.starts_with("refs/") && !n.ends_with("^{}") && !local.contains(n))
+            .map(|(n, _)| format!(":{n}"))
+            .collect();
+        if deleted.is_empty() {
+            Ok(())
+        } else {
+            self.push(dest, repo_dir, &deleted)
+        }
+    }
+
+    fn git_ls_remote(&self, url: &str) -> Result<Vec<(String, String)>, GitError> {
+        let refs = self.run(
+            format!("Listing refs of {url}"),
+            self.timeouts.update,
+            |callbacks| {
+                let mut remote = Remote::create_detached(url)?;
+                let connection = remote.connect_auth(Direction::Fetch, Some(callbacks()), None)?;
+                list(&connection)
+            },
+        )?;
+        match refs {
+            Some(refs) => Ok(refs),
+            None => self.cli.git_ls_remote(url),
+        }
+    }
+
+    fn git_default_branch(&self, url: &str) -> Result<Option<String>, GitError> {
+        self.run(
+            format!("Getting default branch of {url}"),
+            self.timeouts.update,
+            |callbacks| {
+                let mut remote = Remote::create_detached(url)?;
+                let connection = remote.connect_auth(Direction::Fetch, Some(callbacks()), None)?;
+                match connection.default_branch() {
+                    Ok(branch) => Ok(branch.as_str().map(String::from)),
+                    Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+                    Err(e) => Err(e),
+                }
+            },
+        )
+    }
+
+    fn git_fetch(&self, repo_dir: &Path, url: &str, refspecs: &[String]) -> Result<(), GitError> {
+        self.run(
+            format!("Fetch of {url}"),
+            self.timeouts.update,
+            |callbacks| {
+                let repo = match Repository::open_bare(repo_dir) {
+                    Ok(repo) => repo,
+                    Err(e) if e.code() == ErrorCode::NotFound => Repository::init_bare(repo_dir)?,
+                    Err(e) => return Err(e),
+                };
+                let mut options = FetchOptions::new();
+                options
+                    .remote_callbacks(callbacks())
+                    .download_tags(AutotagOption::None);
+                let mut remote = repo.remote_anonymous(url)?;
+                remote.fetch(refspecs, Some(&mut options), None)
+            },
+        )
+    }
+
+    fn git_is_ancestor(
+        &self,
+        repo_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitError> {
+        self.run(format!("Checking history of {repo_dir:?}"), None, |_| {
+            let repo = Repository::open_bare(repo_dir)?;
+            let descendant = repo.revparse_single(descendant)?.peel_to_commit()?.id();
+            let ancestor = match repo.revparse_single(ancestor) {
+                Ok(a) => a.peel_to_commit()?.id(),
+                Err(e) if e.code() == ErrorCode::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            Ok(ancestor == descendant || repo.graph_descendant_of(descendant, ancestor)?)
+        })
+    }
+}
@@ -0,0 +1,382 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ledger::Refs;
+
+/// Largest number or percentage of destination refs a push may delete or rewrite
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "RawThreshold")]
+pub enum Threshold {
+    Count(usize),
+    Percent(f64),
+}
+
+/// A threshold as written in a description, `10` or `"10%"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawThreshold {
+    Count(usize),
+    Text(String),
+}
+
+impl TryFrom<RawThreshold> for Threshold {
+    type Error = String;
+
+    fn try_from(raw: RawThreshold) -> Result<Threshold, String> {
+        match raw {
+            RawThreshold::Count(n) => Ok(Threshold::Count(n)),
+            RawThreshold::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Threshold, String> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(p) => match p.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Threshold::Percent(p)),
+                _ => Err(format!("Invalid percentage: {s}, expected 0% to 100%")),
+            },
+            None => s
+                .parse()
+                .map(Threshold::Count)
+                .map_err(|_| format!("Invalid threshold: {s}, expected a number or a percentage")),
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Threshold::Count(n) => write!(f, "{n}"),
+            Threshold::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+/// Refs of the destination a push would delete or rewrite
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RefChanges {
+    pub deleted: Vec<String>,
+    /// Tags pointing elsewhere and branches not updated fast-forward
+    pub rewritten: Vec<String>,
+    /// Number of destination refs the push could change
+    pub total: usize,
+}
+
+// Number of refs named in the error message
+const LISTED_REFS: usize = 10;
+
+// Namespaces forges list in ls-remote but hide from pushes, a push never changes them
+const HIDDEN_REFS: [&str; 7] = [
+    "refs/pull/",
+    "refs/pull-requests/",
+    "refs/merge-requests/",
+    "refs/keep-around/",
+    "refs/pipelines/",
+    "refs/environments/",
+    "refs/tmp/",
+];
+
+impl RefChanges {
+    pub fn count(&self) -> usize {
+        self.deleted.len() + self.rewritten.len()
+    }
+
+    pub fn exceeds(&self, threshold: Threshold) -> bool {
+        match threshold {
+            Threshold::Count(n) => self.count() > n,
+            Threshold::Percent(p) => self.count() as f64 > self.total as f64 * p / 100.0,
+        }
+    }
+}
+
+impl fmt::Display for RefChanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} deleted and {} rewritten of {} refs",
+            self.deleted.len(),
+            self.rewritten.len(),
+            self.total
+        )?;
+        let refs: Vec<&str> = self
+            .deleted
+            .iter()
+            .chain(self.rewritten.iter())
+            .map(String::as_str)
+            .collect();
+        if !refs.is_empty() {
+            write!(f, " ({}", refs[..refs.len().min(LISTED_REFS)].join(", "))?;
+            if refs.len() > LISTED_REFS {
+                write!(f, ", ...")?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep the refs a push can update, without `HEAD` and peeled tags
+fn pushable(refs: &[(String, String)]) -> HashMap<&str, &str> {
+    refs.iter()
+        .filter(|(n, _)| n.starts_with("refs/") && !n.ends_with("^{}"))
+        .map(|(n, id)| (n.as_str(), id.as_str()))
+        .collect()
+}
+
+/// Keep the destination refs a push can change, without the namespaces hidden by forges
+fn changeable(refs: &[(String, String)]) -> HashMap<&str, &str> {
+    let mut refs = pushable(refs);
+    refs.retain(|n, _| !HIDDEN_REFS.iter().any(|h| n.starts_with(h)));
+    refs
+}
+
+/// Match `name` against a refspec side with at most one `*`, returning the part matched by it
+fn match_pattern<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => name
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)
+            .filter(|m| !m.is_empty()),
+        None => (pattern == name).then_some(""),
+    }
+}
+
+/// The destination ref each local ref is pushed to, together with the deleted refs
+fn map_refspec<'a>(
+    refspec: &[String],
+    local: &HashMap<&'a str, &'a str>,
+    remote: &HashMap<&str, &str>,
+) -> (Vec<(String, &'a str)>, Vec<String>) {
+    let mut mapped = Vec::new();
+    let mut deleted = Vec::new();
+    for spec in refspec {
+        let spec = spec.trim_start_matches('+');
+        let (src, dst) = spec.split_once(':').unwrap_or((spec, spec));
+        if src.is_empty() {
+            deleted.push(dst.to_string());
+            continue;
+        }
+        // Short names are resolved like git does for branches and tags
+        let src_pattern = match src.starts_with("refs/") {
+            true => src.to_string(),
+            false => ["refs/heads/", "refs/tags/"]
+                .iter()
+                .map(|p| format!("{p}{src}"))
+                .find(|n| local.contains_key(n.as_str()))
+                .unwrap_or_else(|| format!("refs/heads/{src}")),
+        };
+        let dst_pattern = match dst.starts_with("refs/") {
+            true => dst.to_string(),
+            false => {
+                let prefix = match src_pattern.starts_with("refs/tags/") {
+                    true => "refs/tags/",
+                    false => "refs/heads/",
+                };
+                format!("{prefix}{dst}")
+            }
+        };
+        for (name, id) in local.iter() {
+            if let Some(m) = match_pattern(&src_pattern, name) {
+                mapped.push((dst_pattern.replacen('*', m, 1), *id));
+            }
+        }
+    }
+    deleted.retain(|d| remote.contains_key(d.as_str()));
+    (mapped, deleted)
+}
+
+/// The refs a push from `local` updates in the destination, without refspec all refs are mirrored
+pub fn pushed_refs(local: &[(String, String)], refspec: &Option<Vec<String>>) -> Refs {
+    let local = pushable(local);
+    match refspec {
+        Some(r) => map_refspec(r, &local, &HashMap::new())
+            .0
+            .into_iter()
+            .map(|(n, id)| (n, id.to_string()))
+            .collect(),
+        None => local
+            .into_iter()
+            .map(|(n, id)| (n.to_string(), id.to_string()))
+            .collect(),
+    }
+}
+
+/// Compute which refs of the destination (`remote`) a push from `local` would delete or rewrite.
+///
+/// Without `refspec` the push mirrors all refs. Refs in namespaces hidden by forges, like
+/// `refs/pull/*`, are ignored as the push can't change them. Branches are rewritten if their new
+/// commit doesn't descend from the old one, checked using `is_ancestor(old, new)`.
+/// Tags are rewritten whenever they change.
+pub fn ref_changes<E>(
+    remote: &[(String, String)],
+    local: &[(String, String)],
+    refspec: &Option<Vec<String>>,
+    mut is_ancestor: impl FnMut(&str, &str) -> Result<bool, E>,
+) -> Result<RefChanges, E> {
+    let remote = changeable(remote);
+    let local = pushable(local);
+
+    let (updates, mut deleted): (Vec<(String, &str)>, Vec<String>) = match refspec {
+        Some(r) => map_refspec(r, &local, &remote),
+        None => (
+            local.iter().map(|(n, id)| (n.to_string(), *id)).collect(),
+            remote
+                .keys()
+                .filter(|n| !local.contains_key(*n))
+                .map(|n| n.to_string())
+                .collect(),
+        ),
+    };
+
+    let mut rewritten = Vec::new();
+    for (name, new) in updates.iter() {
+        let Some(old) = remote.get(name.as_str()) else {
+            continue;
+        };
+        if old == new {
+            continue;
+        }
+        if name.starts_with("refs/tags/") || !is_ancestor(old, new)? {
+            rewritten.push(name.clone());
+        }
+    }
+
+    let total = match refspec {
+        Some(_) => {
+            let updated = updates
+                .iter()
+                .filter(|(n, _)| remote.contains_key(n.as_str()))
+                .count();
+            updated + deleted.len()
+        }
+        None => remote.len(),
+    };
+    deleted.sort();
+    deleted.dedup();
+    rewritten.sort();
+    rewritten.dedup();
+    Ok(RefChanges {
+        deleted,
+        rewritten,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pushed_refs, ref_changes, Threshold};
+
+    fn refs(r: &[(&str, &str)]) -> Vec<(String, String)> {
+        r.iter()
+            .map(|(n, id)| (n.to_string(), id.to_string()))
+            .collect()
+    }
+
+    // Linear history, lower ids are ancestors of higher ones
+    fn is_ancestor(old: &str, new: &str) -> Result<bool, ()> {
+        Ok(old < new)
+    }
+
+    #[test]
+    fn parse_threshold() {
+        assert_eq!("10".parse(), Ok(Threshold::Count(10)));
+        assert_eq!("25%".parse(), Ok(Threshold::Percent(25.0)));
+        assert!("150%".parse::<Threshold>().is_err());
+        assert!("many".parse::<Threshold>().is_err());
+        assert_eq!(
+            serde_yaml::from_str::<Threshold>("\"5%\"").unwrap(),
+            Threshold::Percent(5.0)
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Threshold>("3").unwrap(),
+            Threshold::Count(3)
+        );
+    }
+
+    #[test]
+    fn detect_destructive_changes() {
+        let remote = refs(&[
+            ("HEAD", "2"),
+            ("refs/heads/main", "2"),
+            ("refs/heads/dev", "5"),
+            ("refs/heads/old", "1"),
+            ("refs/tags/v1", "3"),
+            ("refs/tags/v1^{}", "2"),
+            ("refs/pull/1/head", "1"),
+            ("refs/merge-requests/1/head", "1"),
+        ]);
+        let local = refs(&[
+            ("HEAD", "4"),
+            ("refs/heads/main", "4"),
+            ("refs/heads/dev", "3"),
+            ("refs/tags/v1", "6"),
+            ("refs/tags/v2", "7"),
+        ]);
+
+        let changes = ref_changes(&remote, &local, &None, is_ancestor).unwrap();
+        assert_eq!(changes.deleted, ["refs/heads/old"]);
+        assert_eq!(changes.rewritten, ["refs/heads/dev", "refs/tags/v1"]);
+        assert_eq!(changes.total, 4);
+        assert!(changes.exceeds(Threshold::Count(2)));
+        assert!(!changes.exceeds(Threshold::Count(3)));
+        assert!(changes.exceeds(Threshold::Percent(50.0)));
+        assert!(!changes.exceeds(Threshold::Percent(75.0)));
+
+        // Only the refs matched by the refspec are changed
+        let refspec = Some(vec![
+            "main".to_string(),
+            "+refs/tags/*:refs/tags/*".to_string(),
+        ]);
+        let changes = ref_changes(&remote, &local, &refspec, is_ancestor).unwrap();
+        assert!(changes.deleted.is_empty());
+        assert_eq!(changes.rewritten, ["refs/tags/v1"]);
+
+        assert_eq!(changes.total, 2);
+
+        let refspec = Some(vec!["main:dev".to_string(), ":refs/heads/old".to_string()]);
+        let changes = ref_changes(&remote, &local, &refspec, is_ancestor).unwrap();
+        assert_eq!(changes.deleted, ["refs/heads/old"]);
+        assert_eq!(changes.rewritten, ["refs/heads/dev"]);
+        assert_eq!(changes.total, 2);
+    }
+
+    #[test]
+    fn refs_pushed_by_refspec() {
+        let local = refs(&[
+            ("HEAD", "1"),
+            ("refs/heads/main", "1"),
+            ("refs/heads/dev", "2"),
+            ("refs/tags/v1", "3"),
+            ("refs/tags/v1^{}", "1"),
+        ]);
+
+        let mirrored = pushed_refs(&local, &None);
+        assert_eq!(
+            mirrored.keys().collect::<Vec<_>>(),
+            ["refs/heads/dev", "refs/heads/main", "refs/tags/v1"]
+        );
+
+        let refspec = Some(vec![
+            "+main:refs/heads/upstream".to_string(),
+            "refs/tags/*:refs/tags/*".to_string(),
+            ":refs/heads/old".to_string(),
+        ]);
+        let pushed = pushed_refs(&local, &refspec);
+        assert_eq!(
+            pushed.into_iter().collect::<Vec<_>>(),
+            refs(&[("refs/heads/upstream", "1"), ("refs/tags/v1", "3")])
+        );
+    }
+}
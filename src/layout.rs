@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Used to create sane local directory names
+use slug::slugify;
+
+// Used to make hashed directory names unique
+use sha2::{Digest, Sha256};
+
+// Used to select the layout on the command line
+use clap::ValueEnum;
+
+// Number of hex digits of the origin hash appended in the hashed layout
+const HASH_LEN: usize = 12;
+
+/// How the working repositories are named inside the mirror directory
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// `<slug of the origin>`, different origins can end up in the same directory
+    #[default]
+    Slug,
+    /// `<host>/<path>.git`, following the structure of the origin
+    Path,
+    /// `<slug of the origin>-<hash of the origin>`
+    Hashed,
+}
+
+impl Layout {
+    /// Directory of the working repository of `origin`
+    pub fn repo_dir(&self, mirror_dir: &Path, origin: &str) -> PathBuf {
+        match self {
+            Layout::Slug => mirror_dir.join(slugify(origin)),
+            Layout::Path => {
+                let (host, path) = split_origin(origin);
+                let mut dir = mirror_dir.join(encode(&host.to_lowercase()));
+                let mut components: Vec<&str> =
+                    path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+                let name = components.pop().unwrap_or_default();
+                dir.extend(components.into_iter().map(encode_dir));
+                let name = name.strip_suffix(".git").unwrap_or(name);
+                dir.join(format!("{}.git", encode(name)))
+            }
+            Layout::Hashed => mirror_dir.join(hashed_slug(origin)),
+        }
+    }
+}
+
+/// `<slug of name>-<hash of name>`, readable but unique for different names
+pub(crate) fn hashed_slug(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    format!("{}-{}", slugify(name), &hash[..HASH_LEN])
+}
+
+/// Split an origin URL into host and path.
+///
+/// Supports URLs with a scheme, scp-like SSH URLs and local paths,
+/// which are put below the host `local`.
+pub(crate) fn split_origin(origin: &str) -> (&str, &str) {
+    if let Some((scheme, rest)) = origin.split_once("://") {
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        // Drop the user info, the port is kept
+        let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+        match scheme {
+            "file" => ("local", path),
+            _ => (host, path),
+        }
+    } else {
+        match origin.split_once(':') {
+            // A single letter is a Windows drive
+            Some((authority, path)) if authority.len() > 1 && !authority.contains('/') => {
+                let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+                (host, path)
+            }
+            _ => ("local", origin),
+        }
+    }
+}
+
+/// Percent-encode the characters that aren't safe in a directory name, as well as `.` and `..`
+fn encode(component: &str) -> String {
+    if component == "." || component == ".." {
+        return component.replace('.', "%2E");
+    }
+    let mut encoded = String::with_capacity(component.len());
+    for b in component.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Encode a directory above a repository, a `.git` suffix is escaped so repositories don't nest
+fn encode_dir(component: &str) -> String {
+    let encoded = encode(component);
+    // Case-insensitive file systems would still nest them
+    match encoded.len().checked_sub(4) {
+        Some(i) if encoded[i..].eq_ignore_ascii_case(".git") => {
+            format!("{}%2E{}", &encoded[..i], &encoded[i + 1..])
+        }
+        _ => encoded,
+    }
+}
+
+/// Whether a directory is a bare git repository
+fn is_repo(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir()
+}
+
+/// Find the working repositories below `dir`, without looking inside repositories
+pub fn find_repos(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut repos = Vec::new();
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Unable to read directory: {dir:?} ({e})"))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Unable to read directory: {dir:?} ({e})"))?
+            .path();
+        if !path.is_dir() {
+            continue;
+        }
+        if is_repo(&path) {
+            repos.push(path);
+        } else {
+            repos.extend(find_repos(&path)?);
+        }
+    }
+    repos.sort();
+    Ok(repos)
+}
+
+/// Remove the empty parent directories of `dir` up to `mirror_dir`
+pub fn remove_empty_parents(dir: &Path, mirror_dir: &Path) {
+    for parent in dir.ancestors().skip(1) {
+        if parent == mirror_dir
+            || !parent.starts_with(mirror_dir)
+            || fs::remove_dir(parent).is_err()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn repo_dirs() {
+        let m = Path::new("/m");
+        let path = |origin| Layout::Path.repo_dir(m, origin);
+
+        assert_eq!(
+            path("git@gitlab.com:a/b-c.git"),
+            PathBuf::from("/m/gitlab.com/a/b-c.git")
+        );
+        assert_eq!(
+            path("https://user@GitHub.com/a-b/c"),
+            PathBuf::from("/m/github.com/a-b/c.git")
+        );
+        assert_eq!(
+            path("ssh://git@example.org:2222/x/../y z.git"),
+            PathBuf::from("/m/example.org%3A2222/x/%2E%2E/y%20z.git")
+        );
+        // Distinct from the underscore replacing other characters before
+        assert_ne!(path("git@h:a/y_z"), path("git@h:a/y z"));
+        assert_eq!(path("git@h:a/100%"), PathBuf::from("/m/h/a/100%25.git"));
+        // Repositories below a directory looking like a repository don't nest
+        assert_eq!(path("git@h:a.git"), PathBuf::from("/m/h/a.git"));
+        assert_eq!(path("git@h:a.git/b"), PathBuf::from("/m/h/a%2Egit/b.git"));
+        assert_eq!(path("git@h:A.GIT/b"), PathBuf::from("/m/h/A%2EGIT/b.git"));
+        assert_eq!(
+            path("/srv/git/repo"),
+            PathBuf::from("/m/local/srv/git/repo.git")
+        );
+        assert_eq!(
+            path("file:///srv/git/repo.git"),
+            PathBuf::from("/m/local/srv/git/repo.git")
+        );
+
+        // Collide when slugified
+        let (a, b) = ("git@gitlab.com:a/b-c.git", "git@gitlab.com:a-b/c.git");
+        assert_eq!(Layout::Slug.repo_dir(m, a), Layout::Slug.repo_dir(m, b));
+        assert_ne!(Layout::Path.repo_dir(m, a), Layout::Path.repo_dir(m, b));
+        assert_ne!(Layout::Hashed.repo_dir(m, a), Layout::Hashed.repo_dir(m, b));
+        assert!(Layout::Hashed
+            .repo_dir(m, a)
+            .to_string_lossy()
+            .starts_with("/m/git-gitlab-com-a-b-c-git-"));
+    }
+}
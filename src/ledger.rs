@@ -0,0 +1,386 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Time handling
+use time::OffsetDateTime;
+
+// Used for error and debug logging
+use log::warn;
+
+use crate::lock;
+use crate::provider::{Metadata, Mirror};
+
+// File inside the mirror directory holding the ledger
+const LEDGER_FILE: &str = "git-mirror-state.json";
+// Name an unparsable ledger file is moved to before it is replaced
+const CORRUPT_LEDGER_FILE: &str = "git-mirror-state.json.corrupt";
+// Lock held while merging into the ledger file
+const LEDGER_LOCK: &str = "git-mirror-state.lock";
+// Longest time to wait for another instance merging into the ledger file
+const LEDGER_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+// Interval to write the outcomes during a sync run, so they aren't lost if it is interrupted
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Ref name to object id
+pub type Refs = BTreeMap<String, String>;
+
+/// Outcome of the sync runs of a single mirror
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RepoState {
+    /// Label of the provider the mirror belongs to
+    pub label: String,
+    pub origin: String,
+    /// Start of the last sync attempt
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_attempt: Option<OffsetDateTime>,
+    /// Start of the last successful sync
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    /// Duration of the last sync attempt in seconds
+    pub duration_secs: f64,
+    /// Error of the last sync attempt, if it failed
+    pub error: Option<String>,
+    /// Ref tips pushed to the destination by the last successful sync
+    pub refs: Refs,
+    /// Number of failed sync attempts since the last success
+    pub consecutive_failures: u32,
+    /// Metadata last applied to the destination project
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+}
+
+impl RepoState {
+    /// Time before which the mirror shouldn't be synced again because it keeps failing
+    pub fn backoff_until(&self, backoff: &Backoff) -> Option<OffsetDateTime> {
+        if self.consecutive_failures < backoff.after {
+            return None;
+        }
+        // Double the delay with every further failure
+        let delay = 2u32
+            .checked_pow(self.consecutive_failures - backoff.after)
+            .and_then(|f| backoff.base.checked_mul(f))
+            .map_or(backoff.max, |d| d.min(backoff.max));
+        self.last_attempt.map(|t| t + delay)
+    }
+}
+
+/// Policy for retrying persistently failing mirrors less often
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Number of consecutive failures after which a mirror is backing off
+    pub after: u32,
+    /// Delay after reaching `after` failures, doubled with every further failure
+    pub base: Duration,
+    /// Longest delay between two attempts
+    pub max: Duration,
+}
+
+/// Sync state of all mirrors in a mirror directory, keyed by destination
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Ledger {
+    pub repos: BTreeMap<String, RepoState>,
+    /// Destinations recorded since the ledger was loaded
+    #[serde(skip)]
+    recorded: BTreeSet<String>,
+    /// Time the recorded outcomes were last written
+    #[serde(skip)]
+    saved: Option<Instant>,
+}
+
+impl Ledger {
+    /// Load the ledger of a mirror directory, an empty one is returned if none exists yet
+    pub fn load(mirror_dir: &Path) -> Result<Ledger, String> {
+        let path = mirror_dir.join(LEDGER_FILE);
+        match Self::read(&path)? {
+            Some(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Unable to parse state file: {path:?} ({e})")),
+            None => Ok(Ledger::default()),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Option<String>, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Unable to read state file: {path:?} ({e})")),
+        }
+    }
+
+    /// Load the ledger to merge into before saving.
+    ///
+    /// An unparsable ledger file is moved aside, so it is replaced instead of failing every save.
+    fn load_for_merge(mirror_dir: &Path) -> Result<Ledger, String> {
+        let path = mirror_dir.join(LEDGER_FILE);
+        let Some(content) = Self::read(&path)? else {
+            return Ok(Ledger::default());
+        };
+        serde_json::from_str(&content).or_else(|e| {
+            let corrupt_path = mirror_dir.join(CORRUPT_LEDGER_FILE);
+            warn!(
+                "Replacing unparsable state file {:?}, keeping it as {:?} ({})",
+                path, corrupt_path, e
+            );
+            fs::rename(&path, &corrupt_path)
+                .map_err(|e| format!("Unable to move state file: {path:?} ({e})"))?;
+            Ok(Ledger::default())
+        })
+    }
+
+    /// Write the ledger to the mirror directory.
+    ///
+    /// The file is replaced atomically, so readers never see a partial ledger.
+    pub fn save(&self, mirror_dir: &Path) -> Result<(), String> {
+        let path = mirror_dir.join(LEDGER_FILE);
+        let tmp_path = mirror_dir.join(format!("{LEDGER_FILE}.tmp"));
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Unable to serialize state ({e})"))?;
+        fs::write(&tmp_path, content)
+            .map_err(|e| format!("Unable to write state file: {tmp_path:?} ({e})"))?;
+        fs::rename(&tmp_path, &path)
+            .map_err(|e| format!("Unable to replace state file: {path:?} ({e})"))
+    }
+
+    /// Write the outcomes recorded since loading to the ledger file of the mirror directory.
+    ///
+    /// Outcomes recorded by other instances in the meantime are kept.
+    pub fn save_recorded(&mut self, mirror_dir: &Path) -> Result<(), String> {
+        let _lock = lock::lock(&mirror_dir.join(LEDGER_LOCK), LEDGER_LOCK_TIMEOUT)?;
+        let mut current = Ledger::load_for_merge(mirror_dir)?;
+        for destination in self.recorded.iter() {
+            if let Some(state) = self.repos.get(destination) {
+                current.repos.insert(destination.clone(), state.clone());
+            }
+        }
+        self.saved = Some(Instant::now());
+        current.save(mirror_dir)
+    }
+
+    /// Write the outcomes recorded since loading, if they weren't written during the last minute
+    pub fn save_recorded_if_due(&mut self, mirror_dir: &Path) -> Result<(), String> {
+        match self.saved {
+            Some(saved) if saved.elapsed() < SAVE_INTERVAL => Ok(()),
+            _ => self.save_recorded(mirror_dir),
+        }
+    }
+
+    /// Record the metadata applied to the destination project of a mirror
+    pub fn record_metadata(&mut self, destination: &str, metadata: Metadata) {
+        if let Some(state) = self.repos.get_mut(destination) {
+            self.recorded.insert(destination.to_string());
+            state.metadata = Some(metadata);
+        }
+    }
+
+    /// Record the outcome of a sync attempt.
+    ///
+    /// `result` holds the pushed refs on success, `None` if they didn't change or are unknown.
+    pub fn record(
+        &mut self,
+        label: &str,
+        mirror: &Mirror,
+        start: OffsetDateTime,
+        duration: Duration,
+        result: Result<Option<Refs>, String>,
+    ) {
+        self.recorded.insert(mirror.destination.clone());
+        let state = self.repos.entry(mirror.destination.clone()).or_default();
+        state.label = label.to_string();
+        state.origin = mirror.origin.clone();
+        state.last_attempt = Some(start);
+        state.duration_secs = duration.as_secs_f64();
+        match result {
+            Ok(refs) => {
+                state.last_success = Some(start);
+                state.error = None;
+                state.consecutive_failures = 0;
+                if let Some(refs) = refs {
+                    state.refs = refs;
+                }
+            }
+            Err(e) => {
+                state.error = Some(e);
+                state.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Ledger, Refs, RepoState};
+    use crate::provider::Mirror;
+    use std::time::Duration;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn record_outcomes() {
+        let mirror = Mirror {
+            origin: "git@example.org:a/b.git".to_string(),
+            destination: "git@example.org:c/b.git".to_string(),
+            refspec: None,
+            lfs: false,
+            topics: None,
+            archived: false,
+            max_destructive_changes: None,
+        };
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+        let refs: Refs = [("refs/heads/main".to_string(), "abc".to_string())].into();
+        let mut ledger = Ledger::default();
+
+        ledger.record(
+            "l",
+            &mirror,
+            start,
+            Duration::from_secs(2),
+            Ok(Some(refs.clone())),
+        );
+        ledger.record(
+            "l",
+            &mirror,
+            start,
+            Duration::from_secs(1),
+            Err("e1".to_string()),
+        );
+        ledger.record(
+            "l",
+            &mirror,
+            start,
+            Duration::from_secs(1),
+            Err("e2".to_string()),
+        );
+
+        let state = &ledger.repos[&mirror.destination];
+        assert_eq!(state.consecutive_failures, 2);
+        assert_eq!(state.error.as_deref(), Some("e2"));
+        assert_eq!(state.last_success, Some(start));
+        assert_eq!(state.refs, refs);
+
+        ledger.record("l", &mirror, start, Duration::from_secs(1), Ok(None));
+        let state = &ledger.repos[&mirror.destination];
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.error, None);
+        assert_eq!(state.refs, refs);
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        let loaded: Ledger = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.repos, ledger.repos);
+    }
+
+    #[test]
+    fn backoff() {
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+        let backoff = Backoff {
+            after: 3,
+            base: Duration::from_secs(60),
+            max: Duration::from_secs(600),
+        };
+        let until = |failures| {
+            RepoState {
+                last_attempt: Some(start),
+                consecutive_failures: failures,
+                ..Default::default()
+            }
+            .backoff_until(&backoff)
+            .map(|t| (t - start).whole_seconds())
+        };
+
+        assert_eq!(until(0), None);
+        assert_eq!(until(2), None);
+        assert_eq!(until(3), Some(60));
+        assert_eq!(until(4), Some(120));
+        assert_eq!(until(6), Some(480));
+        assert_eq!(until(7), Some(600));
+        assert_eq!(until(100), Some(600));
+    }
+
+    #[test]
+    fn merge_concurrent_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror_dir = dir.path();
+        let mirror = |destination: &str| Mirror {
+            origin: "git@example.org:a/b.git".to_string(),
+            destination: destination.to_string(),
+            refspec: None,
+            lfs: false,
+            topics: None,
+            archived: false,
+            max_destructive_changes: None,
+        };
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+
+        // Two instances loading the ledger before either saved
+        let mut first = Ledger::load(mirror_dir).unwrap();
+        let mut second = Ledger::load(mirror_dir).unwrap();
+        first.record("l", &mirror("d1"), start, Duration::ZERO, Ok(None));
+        second.record("l", &mirror("d2"), start, Duration::ZERO, Ok(None));
+        first.save_recorded(mirror_dir).unwrap();
+        second.save_recorded(mirror_dir).unwrap();
+
+        let merged = Ledger::load(mirror_dir).unwrap();
+        assert_eq!(merged.repos.keys().collect::<Vec<_>>(), ["d1", "d2"]);
+    }
+
+    #[test]
+    fn save_during_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror_dir = dir.path();
+        let mirror = |destination: &str| Mirror {
+            origin: "git@example.org:a/b.git".to_string(),
+            destination: destination.to_string(),
+            refspec: None,
+            lfs: false,
+            topics: None,
+            archived: false,
+            max_destructive_changes: None,
+        };
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+        let saved = || Ledger::load(mirror_dir).unwrap().repos.len();
+
+        let mut ledger = Ledger::load(mirror_dir).unwrap();
+        ledger.record("l", &mirror("d1"), start, Duration::ZERO, Ok(None));
+        ledger.save_recorded_if_due(mirror_dir).unwrap();
+        assert_eq!(saved(), 1);
+
+        // Written again at the end of the run
+        ledger.record("l", &mirror("d2"), start, Duration::ZERO, Ok(None));
+        ledger.save_recorded_if_due(mirror_dir).unwrap();
+        assert_eq!(saved(), 1);
+        ledger.save_recorded(mirror_dir).unwrap();
+        assert_eq!(saved(), 2);
+    }
+
+    #[test]
+    fn replace_corrupt_ledger() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror_dir = dir.path();
+        let mirror = Mirror {
+            origin: "git@example.org:a/b.git".to_string(),
+            destination: "d1".to_string(),
+            refspec: None,
+            lfs: false,
+            topics: None,
+            archived: false,
+            max_destructive_changes: None,
+        };
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+        std::fs::write(mirror_dir.join("git-mirror-state.json"), "{").unwrap();
+        assert!(Ledger::load(mirror_dir).is_err());
+
+        let mut ledger = Ledger::default();
+        ledger.record("l", &mirror, start, Duration::ZERO, Ok(None));
+        ledger.save_recorded(mirror_dir).unwrap();
+
+        let loaded = Ledger::load(mirror_dir).unwrap();
+        assert_eq!(loaded.repos.keys().collect::<Vec<_>>(), ["d1"]);
+        let corrupt = std::fs::read_to_string(mirror_dir.join("git-mirror-state.json.corrupt"));
+        assert_eq!(corrupt.unwrap(), "{");
+    }
+}
@@ -4,23 +4,36 @@
  * SPDX-License-Identifier:     MIT
  */
 
+// GitError keeps the failed command for the error message
+#![allow(clippy::result_large_err)]
+
+mod backup;
 pub mod error;
-mod git;
+pub mod git;
+pub mod guard;
+pub mod layout;
+pub mod ledger;
+pub mod lock;
 pub mod provider;
+mod provision;
+mod refstate;
+pub mod schedule;
+mod server;
+mod webhook;
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
+use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
-
-// File locking
-use fs2::FileExt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread;
+use std::time::Duration;
 
 // Used for error and debug logging
-use log::{debug, error, info, trace};
-
-// Used to create sane local directory names
-use slug::slugify;
+use log::{debug, error, info, trace, warn};
 
 // Macros for serde
 #[macro_use]
@@ -28,37 +41,335 @@ extern crate serde_derive;
 
 // Used to allow multiple paralell sync tasks
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::ThreadPool;
 
 // Time handling
 use time::OffsetDateTime;
 
-use junit_report::{ReportBuilder, TestCase, TestCaseBuilder, TestSuite, TestSuiteBuilder};
+// Used to select the git backend on the command line
+use clap::ValueEnum;
+
+use junit_report::{
+    ReportBuilder, TestCase, TestCaseBuilder, TestResult, TestSuite, TestSuiteBuilder,
+};
 
 // Monitoring;
+use prometheus::core::Collector;
 use prometheus::register_gauge_vec;
-use prometheus::{Encoder, TextEncoder};
+use prometheus::{Encoder, Gauge, GaugeVec, Opts, TextEncoder};
+
+use provider::{Metadata, Mirror, MirrorError, MirrorResult, Provider};
+
+use schedule::Schedule;
+
+#[cfg(feature = "gitoxide")]
+use git::Gitoxide;
+#[cfg(feature = "libgit2")]
+use git::Libgit2;
+use git::{Git, GitError, GitWrapper, Timeouts};
+
+use guard::{RefChanges, Threshold};
+
+use backup::BACKUP_REPO;
+
+use layout::Layout;
 
-use provider::{MirrorError, MirrorResult, Provider};
+use lock::{Lock, LockMode};
 
-use git::{Git, GitWrapper};
+use ledger::{Backoff, Ledger, Refs, RepoState};
+
+use refstate::RefState;
 
 use error::{GitMirrorError, Result};
 
+/// A gauge whose values are collected during a sync run and published once it is done,
+/// so the metrics served meanwhile are the ones of the previous run
+struct RunGauge {
+    published: GaugeVec,
+    run: GaugeVec,
+}
+
+impl RunGauge {
+    fn new(name: &str, help: &str, labels: &[&str]) -> RunGauge {
+        RunGauge {
+            published: register_gauge_vec!(name, help, labels).unwrap(),
+            run: GaugeVec::new(Opts::new(name, help), labels).unwrap(),
+        }
+    }
+
+    fn with_label_values(&self, labels: &[&str]) -> Gauge {
+        self.run.with_label_values(labels)
+    }
+
+    /// Replace the published values with the ones collected in the run
+    fn publish(&self) {
+        self.published.reset();
+        for family in self.run.collect() {
+            for metric in family.get_metric() {
+                let labels: HashMap<&str, &str> = metric
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name(), l.get_value()))
+                    .collect();
+                self.published
+                    .with(&labels)
+                    .set(metric.get_gauge().get_value());
+            }
+        }
+    }
+}
+
+// Metrics are registered once per process
+static PROJ_TOTAL: LazyLock<RunGauge> =
+    LazyLock::new(|| RunGauge::new("git_mirror_total", "Total projects", &["mirror"]));
+static PROJ_SKIP: LazyLock<RunGauge> =
+    LazyLock::new(|| RunGauge::new("git_mirror_skip", "Skipped projects", &["mirror"]));
+static PROJ_FAIL: LazyLock<RunGauge> =
+    LazyLock::new(|| RunGauge::new("git_mirror_fail", "Failed projects", &["mirror"]));
+static PROJ_OK: LazyLock<RunGauge> =
+    LazyLock::new(|| RunGauge::new("git_mirror_ok", "OK projects", &["mirror"]));
+static PROJ_UNCHANGED: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_unchanged",
+        "Unchanged projects, also counted as OK",
+        &["mirror"],
+    )
+});
+static PROJ_BACKOFF: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_backoff",
+        "Projects not synced because they keep failing",
+        &["mirror"],
+    )
+});
+static PROJ_START: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_project_start",
+        "Start of project mirror as unix timestamp",
+        &["origin", "destination", "mirror"],
+    )
+});
+static PROJ_END: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_project_end",
+        "End of project mirror as unix timestamp",
+        &["origin", "destination", "mirror"],
+    )
+});
+static START_TIME: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_start_time",
+        "Start time of the sync as unix timestamp",
+        &["mirror"],
+    )
+});
+static END_TIME: LazyLock<RunGauge> = LazyLock::new(|| {
+    RunGauge::new(
+        "git_mirror_end_time",
+        "End time of the sync as unix timestamp",
+        &["mirror"],
+    )
+});
+
+// JUnit error type of a push refused by the destructive change guard
+const DESTRUCTIVE_CHANGE: &str = "destructive change";
+
+// Bare repository inside the mirror directory used by the gitoxide backend
+const SCRATCH_REPO: &str = "git-mirror-scratch.git";
+
+const GAUGES: [&LazyLock<RunGauge>; 10] = [
+    &PROJ_TOTAL,
+    &PROJ_SKIP,
+    &PROJ_FAIL,
+    &PROJ_OK,
+    &PROJ_UNCHANGED,
+    &PROJ_BACKOFF,
+    &PROJ_START,
+    &PROJ_END,
+    &START_TIME,
+    &END_TIME,
+];
+
+/// Start collecting the metrics of a new sync run
+fn reset_metrics() {
+    for gauge in GAUGES {
+        gauge.run.reset();
+    }
+}
+
+/// Publish the metrics collected in the sync run
+fn publish_metrics() {
+    for gauge in GAUGES {
+        gauge.publish();
+    }
+}
+
+/// Implementation used to run git operations
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GitBackend {
+    /// The git command line
+    #[default]
+    Cli,
+    /// gitoxide, using the git command line for pushing and LFS
+    #[cfg(feature = "gitoxide")]
+    Gitoxide,
+    /// libgit2, using the git command line for LFS
+    #[cfg(feature = "libgit2")]
+    Libgit2,
+}
+
+/// Outcome of a successful mirror of a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// The repository was fetched and pushed, with the pushed refs if they could be listed
+    Synced(Option<Refs>),
+    /// The refs of the origin didn't change since the last push, nothing was done
+    Unchanged,
+}
+
+/// Run a git operation, retrying it if it failed with a transient error
+fn retry<T>(
+    opts: &MirrorOptions,
+    op: impl Fn() -> core::result::Result<T, GitError>,
+) -> core::result::Result<T, GitError> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < opts.retries && e.kind().is_transient() => {
+                attempt += 1;
+                warn!(
+                    "Retrying after {} ({}/{}): {}",
+                    e.kind(),
+                    attempt,
+                    opts.retries,
+                    e
+                );
+                thread::sleep(opts.retry_delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Remove what a failed clone left behind, the next attempt would take it for a working repository
+fn remove_partial_clone(origin_dir: &Path) {
+    if origin_dir.exists() {
+        if let Err(e) = fs::remove_dir_all(origin_dir) {
+            warn!("Unable to remove failed clone {:?} ({})", origin_dir, e);
+        }
+    }
+}
+
+/// Refuse a push to `destination` with more destructive changes than `threshold`
+fn check_destructive_changes(
+    destination: &str,
+    changes: &RefChanges,
+    threshold: Threshold,
+) -> Result<()> {
+    match changes.exceeds(threshold) {
+        true => Err(GitMirrorError::DestructiveChange(format!(
+            "{destination} would have {changes}, more than {threshold}"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// The git backend used by a run, the custom one of the options or a new one
+enum SelectedGit<'a> {
+    Custom(&'a (dyn GitWrapper + Send + Sync + 'static)),
+    New(Box<dyn GitWrapper + Send + Sync>),
+}
+
+impl Deref for SelectedGit<'_> {
+    type Target = dyn GitWrapper + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            SelectedGit::Custom(git) => *git,
+            SelectedGit::New(git) => git.as_ref(),
+        }
+    }
+}
+
+/// Select the custom git backend of the options, or create the one selected by `git_backend`
+fn select_git(opts: &MirrorOptions) -> SelectedGit<'_> {
+    match opts.git {
+        Some(ref git) => SelectedGit::Custom(git.as_ref()),
+        None => SelectedGit::New(new_git(opts)),
+    }
+}
+
+/// Create the git backend selected by `git_backend`
+fn new_git(opts: &MirrorOptions) -> Box<dyn GitWrapper + Send + Sync> {
+    let timeouts = Timeouts {
+        clone: opts.clone_timeout,
+        update: opts.update_timeout,
+        push: opts.push_timeout,
+        lfs: opts.lfs_timeout,
+    };
+    match opts.git_backend {
+        GitBackend::Cli => Box::new(Git::new(
+            opts.git_executable.clone(),
+            opts.mirror_lfs,
+            timeouts,
+        )),
+        #[cfg(feature = "gitoxide")]
+        GitBackend::Gitoxide => Box::new(Gitoxide::new(
+            opts.git_executable.clone(),
+            opts.mirror_lfs,
+            timeouts,
+            opts.mirror_dir.join(SCRATCH_REPO),
+        )),
+        #[cfg(feature = "libgit2")]
+        GitBackend::Libgit2 => Box::new(Libgit2::new(
+            opts.git_executable.clone(),
+            opts.mirror_lfs,
+            timeouts,
+            opts.git_token.clone(),
+        )),
+    }
+}
+
 pub fn mirror_repo(
     origin: &str,
     destination: &str,
     refspec: &Option<Vec<String>>,
     lfs: bool,
+    max_destructive_changes: Option<Threshold>,
     opts: &MirrorOptions,
-) -> Result<()> {
+) -> Result<SyncStatus> {
+    let origin_dir = opts.layout.repo_dir(&opts.mirror_dir, origin);
+    debug!("Using origin dir: {0:?}", origin_dir);
+
+    let git = &*select_git(opts);
+
     if opts.dry_run {
-        return Ok(());
+        if let Some(threshold) = max_destructive_changes {
+            // Nothing is fetched, branches whose new history isn't in the working repository yet
+            // count as rewritten
+            let remote = retry(opts, || git.git_ls_remote(destination))?;
+            let local = retry(opts, || git.git_ls_remote(origin))?;
+            let changes = guard::ref_changes(&remote, &local, refspec, |old, new| {
+                let is_ancestor = origin_dir.is_dir()
+                    && git.git_is_ancestor(&origin_dir, old, new).unwrap_or(false);
+                Ok::<bool, GitError>(is_ancestor)
+            })?;
+            debug!("Push to {} would have {}", destination, changes);
+            check_destructive_changes(destination, &changes, threshold)?;
+        }
+        return Ok(SyncStatus::Synced(None));
     }
 
-    let origin_dir = Path::new(&opts.mirror_dir).join(slugify(origin));
-    debug!("Using origin dir: {0:?}", origin_dir);
+    if let Some(parent) = origin_dir.parent() {
+        fs::create_dir_all(parent).map_err(|e| {
+            GitMirrorError::GenericError(format!("Unable to create directory: {parent:?} ({e})"))
+        })?;
+    }
 
-    let git = Git::new(opts.git_executable.clone(), opts.mirror_lfs);
+    // Destinations of the same origin share the working repository, also within one instance
+    let _lock = lock::lock(&lock::repo_lock_path(&origin_dir), opts.lock_timeout).map_err(|e| {
+        GitMirrorError::GenericError(format!("Unable to lock working repository: {e}"))
+    })?;
 
     git.git_version()?;
 
@@ -66,23 +377,97 @@ pub fn mirror_repo(
         git.git_lfs_version()?;
     }
 
+    let ref_state = if opts.skip_unchanged {
+        let state = RefState {
+            refspec: refspec.clone(),
+            lfs: opts.mirror_lfs && lfs,
+            refs: retry(opts, || git.git_ls_remote(origin))?
+                .into_iter()
+                .collect(),
+        };
+        if origin_dir.is_dir() && refstate::is_unchanged(&origin_dir, destination, &state) {
+            info!("Unchanged since last push to {}", destination);
+            return Ok(SyncStatus::Unchanged);
+        }
+        Some(state)
+    } else {
+        None
+    };
+
     if origin_dir.is_dir() {
         info!("Local Update for {}", origin);
 
-        git.git_update_mirror(origin, &origin_dir, lfs)?;
+        retry(opts, || git.git_update_mirror(origin, &origin_dir, lfs))?;
     } else if !origin_dir.exists() {
         info!("Local Checkout for {}", origin);
 
-        git.git_clone_mirror(origin, &origin_dir, lfs)?;
+        retry(opts, || {
+            git.git_clone_mirror(origin, &origin_dir, lfs)
+                .inspect_err(|_| remove_partial_clone(&origin_dir))
+        })?;
     } else {
         return Err(GitMirrorError::GenericError(format!(
             "Local origin dir is a file: {origin_dir:?}"
         )));
     }
 
+    if max_destructive_changes.is_some() || opts.backup_refs {
+        let remote = retry(opts, || git.git_ls_remote(destination))?;
+        let local = git.git_ls_remote(&origin_dir.to_string_lossy())?;
+        let changes = guard::ref_changes(&remote, &local, refspec, |old, new| {
+            git.git_is_ancestor(&origin_dir, old, new)
+        })?;
+        debug!("Push to {} has {}", destination, changes);
+
+        if let Some(threshold) = max_destructive_changes {
+            check_destructive_changes(destination, &changes, threshold)?;
+        }
+
+        if opts.backup_refs && changes.count() > 0 {
+            let names: Vec<String> = changes
+                .deleted
+                .iter()
+                .chain(changes.rewritten.iter())
+                .cloned()
+                .collect();
+            let backup_repo = opts.mirror_dir.join(BACKUP_REPO);
+            // Taken once, so a retry doesn't start another backup
+            let timestamp = backup::timestamp(OffsetDateTime::now_utc());
+            retry(opts, || {
+                backup::backup(git, &backup_repo, destination, &names, &timestamp)
+            })?;
+            if let Some(ref remote) = opts.backup_remote {
+                retry(opts, || {
+                    backup::push(git, &backup_repo, destination, &names, &timestamp, remote)
+                })?;
+            }
+            info!(
+                "Saved {} refs of {} in backup {}",
+                names.len(),
+                destination,
+                timestamp
+            );
+        }
+    }
+
     info!("Push to destination {}", destination);
 
-    git.git_push_mirror(destination, &origin_dir, refspec, lfs)?;
+    retry(opts, || {
+        git.git_push_mirror(destination, &origin_dir, refspec, lfs)
+    })?;
+
+    // The push succeeded, the recorded refs are only informational
+    let refs = match git.git_ls_remote(&origin_dir.to_string_lossy()) {
+        Ok(local) => Some(guard::pushed_refs(&local, refspec)),
+        Err(e) => {
+            warn!("Unable to list the refs pushed to {} ({})", destination, e);
+            None
+        }
+    };
+
+    if let Some(state) = ref_state {
+        refstate::store(&origin_dir, destination, state).map_err(GitMirrorError::GenericError)?;
+    }
 
     if opts.remove_workrepo {
         fs::remove_dir_all(&origin_dir).map_err(|e| {
@@ -94,184 +479,364 @@ pub fn mirror_repo(
         })?;
     }
 
-    Ok(())
+    Ok(SyncStatus::Synced(refs))
 }
 
-fn run_sync_task(v: &[MirrorResult], label: &str, opts: &MirrorOptions) -> TestSuite {
-    // Give the work to the worker pool
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(opts.worker_count)
-        .build_global()
-        .unwrap();
-
-    let proj_total =
-        register_gauge_vec!("git_mirror_total", "Total projects", &["mirror"]).unwrap();
-    let proj_skip =
-        register_gauge_vec!("git_mirror_skip", "Skipped projects", &["mirror"]).unwrap();
-    let proj_fail = register_gauge_vec!("git_mirror_fail", "Failed projects", &["mirror"]).unwrap();
-    let proj_ok = register_gauge_vec!("git_mirror_ok", "OK projects", &["mirror"]).unwrap();
-    let proj_start = register_gauge_vec!(
-        "git_mirror_project_start",
-        "Start of project mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    )
-    .unwrap();
-    let proj_end = register_gauge_vec!(
-        "git_mirror_project_end",
-        "End of project mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    )
-    .unwrap();
+/// A mirror job together with the label of the provider it originates from
+type LabeledMirrorResult = (String, MirrorResult);
+type LabeledMirror = (String, Mirror);
+
+/// Mirror a single repository, record the outcome in the ledger and report the result
+fn sync_mirror(
+    i: usize,
+    total: usize,
+    label: &str,
+    x: &Mirror,
+    opts: &MirrorOptions,
+    ledger: &Mutex<Ledger>,
+    synced: &Mutex<Vec<LabeledMirror>>,
+) -> TestCase {
+    let start = OffsetDateTime::now_utc();
+    let name = format!("{} -> {}", x.origin, x.destination);
+    println!(
+        "START {}/{} [{}]: {}",
+        i,
+        total,
+        OffsetDateTime::now_utc(),
+        name
+    );
+    PROJ_START
+        .with_label_values(&[&x.origin, &x.destination, label])
+        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+    let refspec = match &x.refspec {
+        Some(r) => {
+            debug!("Using repo specific refspec: {:?}", r);
+            &x.refspec
+        }
+        None => {
+            match opts.refspec.clone() {
+                Some(r) => {
+                    debug!("Using global custom refspec: {:?}", r);
+                }
+                None => {
+                    debug!("Using no custom refspec.");
+                }
+            }
+            &opts.refspec
+        }
+    };
+    trace!("Refspec used: {:?}", refspec);
+    let max_destructive_changes = x.max_destructive_changes.or(opts.max_destructive_changes);
+    let result = mirror_repo(
+        &x.origin,
+        &x.destination,
+        refspec,
+        x.lfs,
+        max_destructive_changes,
+        opts,
+    );
+    {
+        let mut ledger = ledger.lock().unwrap();
+        ledger.record(
+            label,
+            x,
+            start,
+            (OffsetDateTime::now_utc() - start).unsigned_abs(),
+            match result {
+                Ok(SyncStatus::Synced(ref refs)) => Ok(refs.clone()),
+                Ok(SyncStatus::Unchanged) => Ok(None),
+                Err(ref e) => Err(e.to_string()),
+            },
+        );
+        // Keep the outcomes of long runs in case they are interrupted
+        if !opts.dry_run {
+            if let Err(e) = ledger.save_recorded_if_due(&opts.mirror_dir) {
+                error!("{}", e);
+            }
+        }
+    }
+    if result.is_ok() {
+        synced.lock().unwrap().push((label.to_string(), x.clone()));
+    }
+    match result {
+        Ok(status) => {
+            let result = match status {
+                SyncStatus::Synced(_) => "OK",
+                SyncStatus::Unchanged => "UNCHANGED",
+            };
+            println!(
+                "END({}) {}/{} [{}]: {}",
+                result,
+                i,
+                total,
+                OffsetDateTime::now_utc(),
+                name
+            );
+            PROJ_END
+                .with_label_values(&[&x.origin, &x.destination, label])
+                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+            PROJ_OK.with_label_values(&[label]).inc();
+            let mut tc = TestCaseBuilder::success(&name, OffsetDateTime::now_utc() - start);
+            if matches!(status, SyncStatus::Unchanged) {
+                PROJ_UNCHANGED.with_label_values(&[label]).inc();
+                tc.set_system_out("unchanged");
+            }
+            tc.build()
+        }
+        Err(e) => {
+            println!(
+                "END(FAIL) {}/{} [{}]: {} ({})",
+                i,
+                total,
+                OffsetDateTime::now_utc(),
+                name,
+                e
+            );
+            PROJ_END
+                .with_label_values(&[&x.origin, &x.destination, label])
+                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+            PROJ_FAIL.with_label_values(&[label]).inc();
+            error!("Unable to sync repo {} ({})", name, e);
+            TestCaseBuilder::error(
+                &name,
+                OffsetDateTime::now_utc() - start,
+                &match e {
+                    GitMirrorError::GitError(ref g) => g.kind().to_string(),
+                    GitMirrorError::DestructiveChange(_) => DESTRUCTIVE_CHANGE.to_string(),
+                    _ => "sync error".to_string(),
+                },
+                &format!("{e:?}"),
+            )
+            .build()
+        }
+    }
+}
+
+/// Load the ledger to record the outcome of a sync run in.
+///
+/// An unparsable ledger is replaced when saving, losing the history is preferred over not syncing.
+fn load_ledger(opts: &MirrorOptions) -> Mutex<Ledger> {
+    Mutex::new(Ledger::load(&opts.mirror_dir).unwrap_or_else(|e| {
+        error!("Starting with an empty state: {}", e);
+        Ledger::default()
+    }))
+}
+
+fn save_ledger(ledger: Mutex<Ledger>, opts: &MirrorOptions) {
+    if opts.dry_run {
+        trace!("Skipping state update in dry run");
+        return;
+    }
+    if let Err(e) = ledger.into_inner().unwrap().save_recorded(&opts.mirror_dir) {
+        error!("{}", e);
+    }
+}
+
+/// Check if a mirror failed too often in a row to be synced in this run
+fn backoff_until(
+    x: &Mirror,
+    opts: &MirrorOptions,
+    ledger: &Mutex<Ledger>,
+) -> Option<OffsetDateTime> {
+    let backoff = opts.backoff.as_ref()?;
+    let until = ledger
+        .lock()
+        .unwrap()
+        .repos
+        .get(&x.destination)?
+        .backoff_until(backoff)?;
+    (until > OffsetDateTime::now_utc()).then_some(until)
+}
 
+fn run_sync_task(
+    v: &[LabeledMirrorResult],
+    opts: &MirrorOptions,
+    pool: &ThreadPool,
+    synced: &Mutex<Vec<LabeledMirror>>,
+) -> Vec<TestCase> {
     let total = v.len();
-    let results = v
-        .par_iter()
-        .enumerate()
-        .map(|(i, x)| {
-            proj_total.with_label_values(&[label]).inc();
-            let start = OffsetDateTime::now_utc();
-            match x {
-                Ok(x) => {
-                    let name = format!("{} -> {}", x.origin, x.destination);
-                    let proj_fail = proj_fail.clone();
-                    let proj_ok = proj_ok.clone();
-                    let proj_start = proj_start.clone();
-                    let proj_end = proj_end.clone();
-                    let label = label.to_string();
-                    println!(
-                        "START {}/{} [{}]: {}",
-                        i,
-                        total,
-                        OffsetDateTime::now_utc(),
-                        name
-                    );
-                    proj_start
-                        .with_label_values(&[&x.origin, &x.destination, &label])
-                        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                    let refspec = match &x.refspec {
-                        Some(r) => {
-                            debug!("Using repo specific refspec: {:?}", r);
-                            &x.refspec
-                        }
-                        None => {
-                            match opts.refspec.clone() {
-                                Some(r) => {
-                                    debug!("Using global custom refspec: {:?}", r);
-                                }
-                                None => {
-                                    debug!("Using no custom refspec.");
-                                }
-                            }
-                            &opts.refspec
-                        }
-                    };
-                    trace!("Refspec used: {:?}", refspec);
-                    match mirror_repo(&x.origin, &x.destination, refspec, x.lfs, opts) {
-                        Ok(_) => {
+    let ledger = load_ledger(opts);
+    // Give the work to the worker pool
+    let results = pool.install(|| {
+        v.par_iter()
+            .enumerate()
+            .map(|(i, (label, x))| {
+                PROJ_TOTAL.with_label_values(&[label]).inc();
+                let start = OffsetDateTime::now_utc();
+                match x {
+                    Ok(x) => match backoff_until(x, opts, &ledger) {
+                        Some(until) => {
                             println!(
-                                "END(OK) {}/{} [{}]: {}",
+                                "BACKOFF {}/{} [{}]: {} -> {} (until {})",
                                 i,
                                 total,
                                 OffsetDateTime::now_utc(),
-                                name
+                                x.origin,
+                                x.destination,
+                                until
                             );
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                            proj_ok.with_label_values(&[&label]).inc();
-                            TestCaseBuilder::success(&name, OffsetDateTime::now_utc() - start)
+                            PROJ_BACKOFF.with_label_values(&[label]).inc();
+                            let name = format!("{} -> {}", x.origin, x.destination);
+                            // Skipped test cases can't carry a message, mark them by class name
+                            TestCaseBuilder::skipped(&name)
+                                .set_classname("backoff")
                                 .build()
                         }
-                        Err(e) => {
-                            println!(
-                                "END(FAIL) {}/{} [{}]: {} ({})",
-                                i,
-                                total,
-                                OffsetDateTime::now_utc(),
-                                name,
-                                e
-                            );
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                            proj_fail.with_label_values(&[&label]).inc();
-                            error!("Unable to sync repo {} ({})", name, e);
-                            TestCaseBuilder::error(
-                                &name,
-                                OffsetDateTime::now_utc() - start,
-                                "sync error",
-                                &format!("{e:?}"),
-                            )
-                            .build()
-                        }
-                    }
-                }
-                Err(e) => {
-                    proj_skip.with_label_values(&[label]).inc();
-                    let duration = OffsetDateTime::now_utc() - start;
-
-                    match e {
-                        MirrorError::Description(d, se) => {
-                            error!("Error parsing YAML: {}, Error: {:?}", d, se);
-                            TestCaseBuilder::error("", duration, "parse error", &format!("{e:?}"))
+                        None => sync_mirror(i, total, label, x, opts, &ledger, synced),
+                    },
+                    Err(e) => {
+                        PROJ_SKIP.with_label_values(&[label]).inc();
+                        let duration = OffsetDateTime::now_utc() - start;
+
+                        match e {
+                            MirrorError::Description(d, se) => {
+                                error!("Error parsing YAML: {}, Error: {:?}", d, se);
+                                TestCaseBuilder::error(
+                                    "",
+                                    duration,
+                                    "parse error",
+                                    &format!("{e:?}"),
+                                )
                                 .build()
-                        }
-                        MirrorError::Skip(url) => {
-                            println!(
-                                "SKIP {}/{} [{}]: {}",
-                                i,
-                                total,
-                                OffsetDateTime::now_utc(),
-                                url
-                            );
-                            TestCaseBuilder::skipped(url).build()
+                            }
+                            MirrorError::Provider(url, pe) => {
+                                error!("Error getting mirror: {}, Error: {}", url, pe);
+                                TestCaseBuilder::error(
+                                    "",
+                                    duration,
+                                    "provider error",
+                                    &format!("{e:?}"),
+                                )
+                                .build()
+                            }
+                            MirrorError::Skip(url, ..) => {
+                                println!(
+                                    "SKIP {}/{} [{}]: {}",
+                                    i,
+                                    total,
+                                    OffsetDateTime::now_utc(),
+                                    url
+                                );
+                                TestCaseBuilder::skipped(url).build()
+                            }
                         }
                     }
                 }
-            }
-        })
-        .collect::<Vec<TestCase>>();
+            })
+            .collect::<Vec<TestCase>>()
+    });
+    save_ledger(ledger, opts);
 
     let success = results.iter().filter(|x| x.is_success()).count();
-    let ts = TestSuiteBuilder::new("Sync Job")
-        .add_testcases(results)
-        .build();
     println!(
         "DONE [{2}]: {0}/{1}",
         success,
         total,
         OffsetDateTime::now_utc()
     );
-    ts
+    results
 }
 
 pub struct MirrorOptions {
     pub mirror_dir: PathBuf,
+    /// How the working repositories are named inside the mirror directory
+    pub layout: Layout,
+    /// How instances running against the same mirror directory are kept apart
+    pub lock_mode: LockMode,
+    /// Longest time to wait for another sync of the same working repository
+    pub lock_timeout: Duration,
     pub dry_run: bool,
     pub metrics_file: Option<PathBuf>,
     pub junit_file: Option<PathBuf>,
     pub worker_count: usize,
     pub git_executable: String,
+    /// Implementation used to run git operations
+    pub git_backend: GitBackend,
+    /// Token used by the libgit2 backend to authenticate to HTTPS remotes
+    pub git_token: Option<String>,
+    /// Custom git backend used instead of the one selected by `git_backend`,
+    /// e.g. to test the sync without real git remotes
+    pub git: Option<Box<dyn GitWrapper + Send + Sync>>,
     pub refspec: Option<Vec<String>>,
     pub remove_workrepo: bool,
     pub fail_on_sync_error: bool,
     pub mirror_lfs: bool,
+    /// Skip repositories whose origin refs didn't change since the last push
+    pub skip_unchanged: bool,
+    /// Update the default branch, topics and archived state of the destination project after a push
+    pub sync_metadata: bool,
+    /// Refuse to push if more refs of the destination would be deleted or rewritten
+    pub max_destructive_changes: Option<Threshold>,
+    /// Save the refs of the destination a push deletes or rewrites in the backup repository
+    pub backup_refs: bool,
+    /// Remote the saved refs are pushed to as well
+    pub backup_remote: Option<String>,
+    /// Longest time a clone may take before it is killed
+    pub clone_timeout: Option<Duration>,
+    /// Longest time fetching updates or listing refs may take before it is killed
+    pub update_timeout: Option<Duration>,
+    /// Longest time a push may take before it is killed
+    pub push_timeout: Option<Duration>,
+    /// Longest time fetching LFS objects may take before it is killed
+    pub lfs_timeout: Option<Duration>,
+    /// Number of times a git command failing with a transient error is retried
+    pub retries: u32,
+    /// Time to wait before retrying a git command
+    pub retry_delay: Duration,
+    /// Sync repositories failing persistently less often
+    pub backoff: Option<Backoff>,
+    /// Address to serve `/metrics`, `/healthz` and `/readyz` on
+    pub metrics_listen: Option<String>,
+    /// Address to accept push webhooks on in daemon mode
+    pub webhook_listen: Option<String>,
+    /// Secret token used to verify webhooks
+    pub webhook_secret: Option<String>,
 }
 
-pub fn do_mirror(provider: Box<dyn Provider>, opts: &MirrorOptions) -> Result<()> {
-    let start_time = register_gauge_vec!(
-        "git_mirror_start_time",
-        "Start time of the sync as unix timestamp",
-        &["mirror"]
-    )
-    .unwrap();
-    let end_time = register_gauge_vec!(
-        "git_mirror_end_time",
-        "End time of the sync as unix timestamp",
-        &["mirror"]
-    )
-    .unwrap();
+/// The defaults of the command line
+impl Default for MirrorOptions {
+    fn default() -> Self {
+        MirrorOptions {
+            mirror_dir: PathBuf::from("./mirror-dir"),
+            layout: Layout::default(),
+            lock_mode: LockMode::default(),
+            lock_timeout: Duration::from_secs(600),
+            dry_run: false,
+            metrics_file: None,
+            junit_file: None,
+            worker_count: 1,
+            git_executable: "git".to_string(),
+            git_backend: GitBackend::default(),
+            git_token: None,
+            git: None,
+            refspec: None,
+            remove_workrepo: false,
+            fail_on_sync_error: false,
+            mirror_lfs: false,
+            skip_unchanged: false,
+            sync_metadata: false,
+            max_destructive_changes: None,
+            backup_refs: false,
+            backup_remote: None,
+            clone_timeout: None,
+            update_timeout: None,
+            push_timeout: None,
+            lfs_timeout: None,
+            retries: 2,
+            retry_delay: Duration::from_secs(10),
+            backoff: None,
+            metrics_listen: None,
+            webhook_listen: None,
+            webhook_secret: None,
+        }
+    }
+}
 
+/// Make sure the mirror directory exists and lock it against other instances.
+///
+/// A shared lock allows other instances using per repository locks to work in the mirror directory.
+/// The lock is held until it is dropped.
+fn lock_mirror_dir(opts: &MirrorOptions, exclusive: bool) -> Result<Lock> {
     // Make sure the mirror directory exists
     trace!("Create mirror directory at {:?}", opts.mirror_dir);
     fs::create_dir_all(&opts.mirror_dir).map_err(|e| {
@@ -281,53 +846,673 @@ pub fn do_mirror(provider: Box<dyn Provider>, opts: &MirrorOptions) -> Result<()
         ))
     })?;
 
-    // Check that only one instance is running against a mirror directory
-    let lockfile_path = opts.mirror_dir.join("git-mirror.lock");
-    let lockfile = fs::File::create(&lockfile_path).map_err(|e| {
-        GitMirrorError::GenericError(format!(
-            "Unable to open lockfile: {:?} ({})",
-            &lockfile_path, e
-        ))
-    })?;
-
-    lockfile.try_lock_exclusive().map_err(|e| {
+    // Check that no other instance is running against the mirror directory
+    let lockfile_path = opts.mirror_dir.join(lock::MIRROR_DIR_LOCK);
+    let lockfile = lock::try_lock(&lockfile_path, exclusive).map_err(|e| {
         GitMirrorError::GenericError(format!(
             "Another instance is already running against the same mirror directory: {:?} ({})",
             &opts.mirror_dir, e
         ))
     })?;
 
-    trace!("Aquired lockfile: {:?}", &lockfile);
+    trace!("Aquired lockfile: {:?}", &lockfile_path);
+
+    Ok(lockfile)
+}
+
+/// Lock the mirror directory for a sync run, depending on the lock mode
+fn lock_mirror_dir_for_sync(opts: &MirrorOptions) -> Result<Lock> {
+    lock_mirror_dir(opts, opts.lock_mode == LockMode::Global)
+}
+
+/// Move the working repositories in the mirror directory to their location in `opts.layout`.
+///
+/// The origin of each repository is read from its git configuration.
+/// Repositories whose new location is already taken are left in place.
+pub fn migrate_layout(opts: &MirrorOptions) -> Result<()> {
+    let _lockfile = lock_mirror_dir(opts, true)?;
+    let git = Git::new(opts.git_executable.clone(), false, Timeouts::default());
+
+    let repos = layout::find_repos(&opts.mirror_dir).map_err(GitMirrorError::GenericError)?;
+    let mut conflicts = 0;
+    for repo_dir in repos {
+        let origin = match git.git_remote_url(&repo_dir) {
+            Ok(origin) => origin,
+            Err(e) => {
+                // E.g. the scratch repository of the gitoxide backend
+                debug!("Skipping {:?} without origin ({})", repo_dir, e);
+                continue;
+            }
+        };
+        let target = opts.layout.repo_dir(&opts.mirror_dir, &origin);
+        if target == repo_dir {
+            continue;
+        }
+        if target.exists() {
+            println!("CONFLICT {repo_dir:?} -> {target:?} ({origin})");
+            conflicts += 1;
+            continue;
+        }
+        println!("MOVE {repo_dir:?} -> {target:?} ({origin})");
+        if opts.dry_run {
+            continue;
+        }
+        let moved = target
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&repo_dir, &target));
+        if let Err(e) = moved {
+            return Err(GitMirrorError::GenericError(format!(
+                "Unable to move {repo_dir:?} to {target:?} ({e})"
+            )));
+        }
+        // Not held by anyone, all syncs are excluded by the lock on the mirror directory
+        let _ = fs::remove_file(lock::repo_lock_path(&repo_dir));
+        layout::remove_empty_parents(&repo_dir, &opts.mirror_dir);
+    }
+
+    if conflicts > 0 {
+        Err(GitMirrorError::GenericError(format!(
+            "{conflicts} repositories not moved because their new location already exists"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Total size of the files below `dir`
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&entry.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{size:.1} {unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1} TiB")
+}
+
+/// Remove the working repositories that don't belong to any mirror of the providers.
+///
+/// With `archive_dir` the repositories are moved there instead of being deleted.
+/// Repositories without a ledger entry, e.g. cloned before it existed, are only removed
+/// if the mirror directory isn't shared. Nothing is removed if a provider couldn't list its mirrors, the state of mirrors
+/// no longer listed is dropped from the ledger.
+pub fn prune(
+    providers: Vec<Box<dyn Provider>>,
+    opts: &MirrorOptions,
+    archive_dir: Option<&Path>,
+) -> Result<()> {
+    let _lockfile = lock_mirror_dir(opts, true)?;
+    let mut ledger = Ledger::load(&opts.mirror_dir).map_err(GitMirrorError::GenericError)?;
+
+    let labels: HashSet<String> = providers.iter().map(|p| p.get_label()).collect();
+    if opts.lock_mode == LockMode::Repo {
+        // Other groups sharing the mirror directory could have mirrors in common with the given ones
+        let mut missing: Vec<&str> = ledger
+            .repos
+            .values()
+            .map(|s| s.label.as_str())
+            .filter(|l| !labels.contains(*l))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        if !missing.is_empty() {
+            return Err(GitMirrorError::GenericError(format!(
+                "Not pruning a shared mirror directory without all its groups, missing: {}",
+                missing.join(", ")
+            )));
+        }
+    }
+
+    // Destinations still in use, skipped ones included
+    let mut destinations: HashSet<String> = HashSet::new();
+    let mut used: HashSet<PathBuf> = HashSet::new();
+    for provider in providers.iter() {
+        let label = provider.get_label();
+        let repos = provider.get_mirror_repos().map_err(|e| {
+            GitMirrorError::GenericError(format!("Unable to get mirror repos from {label} ({e})"))
+        })?;
+        for r in repos {
+            match r {
+                Ok(m) => {
+                    used.insert(opts.layout.repo_dir(&opts.mirror_dir, &m.origin));
+                    destinations.insert(m.destination);
+                }
+                Err(MirrorError::Skip(_, _, destination)) => destinations.extend(destination),
+                // The origin of an invalid description is unknown, its repository could be pruned
+                Err(e @ (MirrorError::Description(..) | MirrorError::Provider(..))) => {
+                    return Err(GitMirrorError::GenericError(format!(
+                        "Not pruning because of an invalid description in {label} ({e:?})"
+                    )))
+                }
+            }
+        }
+    }
+
+    // Only what was synced for the given providers is pruned
+    let is_orphan = |destination: &str, state: &RepoState| {
+        labels.contains(&state.label) && !destinations.contains(destination)
+    };
+    let mut candidates: HashSet<PathBuf> = HashSet::new();
+    for (destination, state) in ledger.repos.iter() {
+        let repo_dir = opts.layout.repo_dir(&opts.mirror_dir, &state.origin);
+        match is_orphan(destination, state) {
+            true => candidates.insert(repo_dir),
+            false => used.insert(repo_dir),
+        };
+    }
+    let repos = layout::find_repos(&opts.mirror_dir).map_err(GitMirrorError::GenericError)?;
+    let own_repos = [
+        opts.mirror_dir.join(BACKUP_REPO),
+        opts.mirror_dir.join(SCRATCH_REPO),
+    ];
+    let mut orphans = Vec::new();
+    for repo_dir in repos
+        .into_iter()
+        .filter(|r| !used.contains(r) && !own_repos.contains(r))
+    {
+        if candidates.contains(&repo_dir) || opts.lock_mode == LockMode::Global {
+            orphans.push(repo_dir);
+        } else {
+            // Without a ledger entry it could belong to another group sharing the mirror directory
+            println!(
+                "UNTRACKED {:?} ({})",
+                repo_dir,
+                format_size(dir_size(&repo_dir))
+            );
+        }
+    }
+
+    let mut reclaimed = 0;
+    let mut count = 0;
+    for repo_dir in orphans {
+        let size = dir_size(&repo_dir);
+        let removed = match archive_dir {
+            Some(archive_dir) => {
+                let relative = repo_dir.strip_prefix(&opts.mirror_dir).unwrap_or(&repo_dir);
+                let target = archive_dir.join(relative);
+                println!(
+                    "ARCHIVE {:?} -> {:?} ({})",
+                    repo_dir,
+                    target,
+                    format_size(size)
+                );
+                if opts.dry_run {
+                    Ok(())
+                } else if target.exists() {
+                    Err(format!("{target:?} already exists"))
+                } else {
+                    target
+                        .parent()
+                        .map_or(Ok(()), fs::create_dir_all)
+                        .and_then(|_| fs::rename(&repo_dir, &target))
+                        .map_err(|e| e.to_string())
+                }
+            }
+            None => {
+                println!("PRUNE {:?} ({})", repo_dir, format_size(size));
+                match opts.dry_run {
+                    true => Ok(()),
+                    false => fs::remove_dir_all(&repo_dir).map_err(|e| e.to_string()),
+                }
+            }
+        };
+        match removed {
+            Ok(()) => {
+                reclaimed += size;
+                count += 1;
+                if !opts.dry_run {
+                    // Not held by anyone, all syncs are excluded by the lock on the mirror directory
+                    let _ = fs::remove_file(lock::repo_lock_path(&repo_dir));
+                    layout::remove_empty_parents(&repo_dir, &opts.mirror_dir);
+                }
+            }
+            Err(e) => error!("Unable to remove {:?} ({})", repo_dir, e),
+        }
+    }
+
+    if !opts.dry_run {
+        ledger
+            .repos
+            .retain(|destination, state| !is_orphan(destination, state));
+        ledger
+            .save(&opts.mirror_dir)
+            .map_err(GitMirrorError::GenericError)?;
+    }
+
+    println!(
+        "DONE [{}]: {} {} in {} repositories",
+        OffsetDateTime::now_utc(),
+        match opts.dry_run {
+            true => "Would reclaim",
+            false => "Reclaimed",
+        },
+        format_size(reclaimed),
+        count
+    );
+    Ok(())
+}
+
+/// Create the projects of the manifest that aren't mirrored by `provider` yet
+pub fn provision(provider: &dyn Provider, manifest: &Path, dry_run: bool) -> Result<()> {
+    let projects = provision::load(manifest).map_err(GitMirrorError::GenericError)?;
 
-    // Get the list of repos to sync from gitlabsss
-    let v = provider.get_mirror_repos().map_err(|e| -> GitMirrorError {
-        GitMirrorError::GenericError(format!("Unable to get mirror repos ({e})"))
+    let label = provider.get_label();
+    let repos = provider.get_mirror_repos().map_err(|e| {
+        GitMirrorError::GenericError(format!("Unable to get mirror repos from {label} ({e})"))
     })?;
+    // Skipped and archived projects exist as well
+    let mirrored: HashSet<String> = repos
+        .into_iter()
+        .filter_map(|r| match r {
+            Ok(m) => Some(m.origin),
+            Err(MirrorError::Skip(_, origin, _)) => origin,
+            Err(MirrorError::Description(..) | MirrorError::Provider(..)) => None,
+        })
+        .collect();
 
-    start_time
-        .with_label_values(&[&provider.get_label()])
-        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+    let mut created = 0;
+    let mut failed = 0;
+    for project in projects {
+        let path = match project.namespace {
+            Some(ref n) => format!("{n}/{}", project.name),
+            None => project.name.clone(),
+        };
+        if mirrored.contains(&project.origin) {
+            println!("EXISTS {} -> {}", project.origin, path);
+            continue;
+        }
+        println!(
+            "CREATE {} -> {} ({})",
+            project.origin,
+            path,
+            project.visibility.as_str()
+        );
+        if dry_run {
+            created += 1;
+            continue;
+        }
+        match provider.create_project(&project) {
+            Ok(url) => {
+                info!("Created {}", url);
+                created += 1;
+            }
+            Err(e) => {
+                error!("Unable to create {} in {} ({})", path, label, e);
+                failed += 1;
+            }
+        }
+    }
 
-    let ts = run_sync_task(&v, &provider.get_label(), opts);
+    println!(
+        "DONE [{}]: {} {} projects in {}",
+        OffsetDateTime::now_utc(),
+        match dry_run {
+            true => "Would create",
+            false => "Created",
+        },
+        created,
+        label
+    );
+    match failed {
+        0 => Ok(()),
+        n => Err(GitMirrorError::GenericError(format!(
+            "Unable to create {n} projects"
+        ))),
+    }
+}
 
-    end_time
-        .with_label_values(&[&provider.get_label()])
-        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+/// List the backups of the refs of `destination`,
+/// or push the refs saved in the backup taken at `timestamp` back to it
+pub fn restore(destination: &str, timestamp: Option<&str>, opts: &MirrorOptions) -> Result<()> {
+    let git = &*select_git(opts);
+    let backup_repo = opts.mirror_dir.join(BACKUP_REPO);
+    let backups = backup::list(git, &backup_repo, destination)?;
+
+    let Some(timestamp) = timestamp else {
+        for (timestamp, refs) in backups.iter() {
+            println!("BACKUP {timestamp} {destination}");
+            for r in refs {
+                println!("    {} {}", r.id, r.name);
+            }
+        }
+        return Ok(());
+    };
+
+    let refs = backups.get(timestamp).ok_or_else(|| {
+        GitMirrorError::GenericError(format!("No backup of {destination} taken at {timestamp}"))
+    })?;
+    for r in refs {
+        println!("RESTORE {} {} -> {}", r.id, r.name, destination);
+    }
+    if !opts.dry_run {
+        let specs: Vec<String> = refs
+            .iter()
+            .map(|r| format!("+{}:{}", r.backup, r.name))
+            .collect();
+        retry(opts, || {
+            git.git_push_mirror(destination, &backup_repo, &Some(specs.clone()), false)
+        })?;
+    }
+    Ok(())
+}
+
+fn build_worker_pool(opts: &MirrorOptions) -> Result<ThreadPool> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.worker_count)
+        .build()
+        .map_err(|e| GitMirrorError::GenericError(format!("Unable to create worker pool ({e})")))
+}
+
+/// Mirror the repositories of all providers in a single sync run.
+///
+/// All providers share the worker pool, the lock on the mirror directory,
+/// the metrics and the JUnit report. Each provider gets its own test suite
+/// in the report, named after its label.
+pub fn do_mirror(providers: Vec<Box<dyn Provider>>, opts: &MirrorOptions) -> Result<()> {
+    let _lockfile = lock_mirror_dir_for_sync(opts)?;
+    let pool = build_worker_pool(opts)?;
+
+    if let Some(ref addr) = opts.metrics_listen {
+        server::start(addr)?;
+    }
+
+    sync_providers(&providers, opts, &pool, &Mutex::new(Vec::new()))
+}
+
+/// Keep running and mirror the repositories of all providers according to the schedule.
+///
+/// The lock on the mirror directory and the worker pool are kept for the
+/// lifetime of the daemon. Failing sync runs are logged and don't stop the daemon.
+pub fn run_daemon(
+    providers: Vec<Box<dyn Provider>>,
+    opts: &MirrorOptions,
+    schedule: &Schedule,
+) -> Result<()> {
+    let _lockfile = lock_mirror_dir_for_sync(opts)?;
+    let pool = build_worker_pool(opts)?;
+
+    if let Some(ref addr) = opts.metrics_listen {
+        server::start(addr)?;
+    }
+
+    // Mirrors of the last sync run, used to resolve webhooks
+    let known_mirrors = Arc::new(Mutex::new(Vec::new()));
+    let (trigger, triggered) = mpsc::channel::<Vec<LabeledMirror>>();
+
+    if let Some(ref addr) = opts.webhook_listen {
+        let secret = opts.webhook_secret.clone().ok_or_else(|| {
+            GitMirrorError::GenericError("A webhook secret is required".to_string())
+        })?;
+        if !schedule.run_on_start() {
+            // Resolve webhooks received before the first sync run
+            *known_mirrors.lock().unwrap() = list_mirrors(&providers);
+        }
+        webhook::start(addr, secret, known_mirrors.clone(), trigger.clone())?;
+    }
+
+    let mut next = if schedule.run_on_start() {
+        OffsetDateTime::now_utc()
+    } else {
+        let now = OffsetDateTime::now_utc();
+        schedule.next_run(now, now)
+    };
+
+    loop {
+        let now = OffsetDateTime::now_utc();
+        if next > now {
+            info!("Next sync run at {}", next);
+            // Sync mirrors triggered by webhooks while waiting for the next run
+            match triggered.recv_timeout((next - now).unsigned_abs()) {
+                Ok(mirrors) => {
+                    sync_triggered(&providers, &mirrors, opts, &pool);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!("Trigger is kept alive"),
+            }
+        }
+
+        let start = OffsetDateTime::now_utc();
+        match sync_providers(&providers, opts, &pool, &known_mirrors) {
+            Ok(_) => info!("Sync run done"),
+            Err(e) => error!("Sync run failed: {}", e),
+        }
+
+        next = schedule.next_run(start, OffsetDateTime::now_utc());
+    }
+}
+
+/// List the mirrors of all providers, failing providers and invalid descriptions are only logged
+fn list_mirrors(providers: &[Box<dyn Provider>]) -> Vec<LabeledMirror> {
+    let mut mirrors = Vec::new();
+    for provider in providers.iter() {
+        let label = provider.get_label();
+        match provider.get_mirror_repos() {
+            Ok(repos) => mirrors.extend(repos.into_iter().flatten().map(|m| (label.clone(), m))),
+            Err(e) => error!("Unable to get mirror repos from {} ({})", label, e),
+        }
+    }
+    mirrors
+}
+
+/// Sync only the given mirrors, e.g. after receiving a webhook
+fn sync_triggered(
+    providers: &[Box<dyn Provider>],
+    mirrors: &[LabeledMirror],
+    opts: &MirrorOptions,
+    pool: &ThreadPool,
+) {
+    let total = mirrors.len();
+    let ledger = load_ledger(opts);
+    let synced = Mutex::new(Vec::new());
+    let results = pool.install(|| {
+        mirrors
+            .par_iter()
+            .enumerate()
+            .map(|(i, (label, x))| {
+                PROJ_TOTAL.with_label_values(&[label]).inc();
+                sync_mirror(i, total, label, x, opts, &ledger, &synced)
+            })
+            .collect::<Vec<TestCase>>()
+    });
+    save_ledger(ledger, opts);
+    sync_metadata(providers, synced.into_inner().unwrap(), opts, pool);
+    // Add the outcome to the metrics of the last sync run
+    publish_metrics();
 
+    let success = results.iter().filter(|x| x.is_success()).count();
+    println!(
+        "DONE(TRIGGERED) [{2}]: {0}/{1}",
+        success,
+        total,
+        OffsetDateTime::now_utc()
+    );
+}
+
+/// Update the metadata of the destination projects of the synced mirrors.
+///
+/// The default branches of the origins are looked up in the worker pool,
+/// the providers are called one after the other. Projects are only updated if their
+/// metadata changed since it was last applied. Failures are only logged.
+fn sync_metadata(
+    providers: &[Box<dyn Provider>],
+    synced: Vec<LabeledMirror>,
+    opts: &MirrorOptions,
+    pool: &ThreadPool,
+) {
+    if !opts.sync_metadata || opts.dry_run || synced.is_empty() {
+        return;
+    }
+
+    let mut supported: HashMap<String, &dyn Provider> = HashMap::new();
+    for provider in providers.iter() {
+        let label = provider.get_label();
+        if provider.supports_metadata() {
+            supported.insert(label, provider.as_ref());
+        } else if synced.iter().any(|(l, _)| *l == label) {
+            warn!("Not updating the metadata of the mirrors of {label}, it isn't supported by the provider");
+        }
+    }
+    let synced: Vec<LabeledMirror> = synced
+        .into_iter()
+        .filter(|(label, _)| supported.contains_key(label))
+        .collect();
+    if synced.is_empty() {
+        return;
+    }
+
+    let git = &*select_git(opts);
+    let default_branches = pool.install(|| {
+        synced
+            .par_iter()
+            .map(
+                |(_, m)| match retry(opts, || git.git_default_branch(&m.origin)) {
+                    Ok(branch) => branch.map(|b| b.trim_start_matches("refs/heads/").to_string()),
+                    Err(e) => {
+                        warn!("Unable to get the default branch of {} ({})", m.origin, e);
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<Option<String>>>()
+    });
+
+    let ledger = load_ledger(opts);
+    for ((label, mirror), default_branch) in synced.iter().zip(default_branches) {
+        let metadata = Metadata {
+            default_branch,
+            topics: mirror.topics.clone(),
+            archived: mirror.archived,
+        };
+        let applied = ledger
+            .lock()
+            .unwrap()
+            .repos
+            .get(&mirror.destination)
+            .and_then(|s| s.metadata.clone());
+        if applied.as_ref() == Some(&metadata) {
+            trace!("Metadata of {} unchanged", mirror.destination);
+            continue;
+        }
+        debug!(
+            "Updating metadata of {}: {:?}",
+            mirror.destination, metadata
+        );
+        match supported[label].update_metadata(mirror, &metadata) {
+            Ok(()) => ledger
+                .lock()
+                .unwrap()
+                .record_metadata(&mirror.destination, metadata),
+            Err(e) => error!(
+                "Unable to update the metadata of {} ({})",
+                mirror.destination, e
+            ),
+        }
+    }
+    save_ledger(ledger, opts);
+}
+
+fn sync_providers(
+    providers: &[Box<dyn Provider>],
+    opts: &MirrorOptions,
+    pool: &ThreadPool,
+    known_mirrors: &Mutex<Vec<LabeledMirror>>,
+) -> Result<()> {
+    reset_metrics();
+
+    // Get the list of repos to sync from all providers
+    let mut v: Vec<LabeledMirrorResult> = Vec::new();
+    let mut suites: Vec<(String, usize)> = Vec::new();
+    let mut provider_errors: Vec<String> = Vec::new();
+    for provider in providers.iter() {
+        let label = provider.get_label();
+        match provider.get_mirror_repos() {
+            Ok(repos) => {
+                suites.push((label.clone(), repos.len()));
+                v.extend(repos.into_iter().map(|r| (label.clone(), r)));
+            }
+            Err(e) => {
+                error!("Unable to get mirror repos from {} ({})", label, e);
+                provider_errors.push(format!("{label}: {e}"));
+            }
+        }
+    }
+
+    *known_mirrors.lock().unwrap() = v
+        .iter()
+        .filter_map(|(label, r)| r.as_ref().ok().map(|m| (label.clone(), m.clone())))
+        .collect();
+
+    for (label, _) in suites.iter() {
+        START_TIME
+            .with_label_values(&[label])
+            .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+    }
+
+    let synced = Mutex::new(Vec::new());
+    let results = run_sync_task(&v, opts, pool, &synced);
+    sync_metadata(providers, synced.into_inner().unwrap(), opts, pool);
+    let refused = results
+        .iter()
+        .filter(|tc| matches!(tc.result, TestResult::Error { ref type_, .. } if type_ == DESTRUCTIVE_CHANGE))
+        .count();
+    let mut results = results.into_iter();
+
+    // Split the results into one test suite per provider
+    let suites = suites
+        .into_iter()
+        .map(|(label, count)| {
+            END_TIME
+                .with_label_values(&[&label])
+                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+            TestSuiteBuilder::new(&label)
+                .add_testcases(results.by_ref().take(count))
+                .build()
+        })
+        .collect::<Vec<TestSuite>>();
+
+    publish_metrics();
     match opts.metrics_file {
         Some(ref f) => write_metrics(f),
         None => trace!("Skipping metrics file creation"),
     };
 
     // Check if any tasks failed
-    let error_count = ts.errors() + ts.failures();
+    let error_count: usize = suites.iter().map(|ts| ts.errors() + ts.failures()).sum();
 
     match opts.junit_file {
-        Some(ref f) => write_junit_report(f, ts),
+        Some(ref f) => write_junit_report(f, suites),
         None => trace!("Skipping junit report"),
     }
 
-    if opts.fail_on_sync_error && error_count > 0 {
+    server::set_ready();
+
+    if !provider_errors.is_empty() {
+        Err(GitMirrorError::GenericError(format!(
+            "Unable to get mirror repos ({})",
+            provider_errors.join(", ")
+        )))
+    } else if refused > 0 {
+        // Needs attention even if sync errors are tolerated
+        Err(GitMirrorError::DestructiveChange(format!(
+            "{refused} repositories refused"
+        )))
+    } else if opts.fail_on_sync_error && error_count > 0 {
         Err(GitMirrorError::SyncError(error_count))
     } else {
         Ok(())
@@ -341,8 +1526,34 @@ fn write_metrics(f: &Path) {
     encoder.encode(&metric_familys, &mut file).unwrap();
 }
 
-fn write_junit_report(f: &Path, ts: TestSuite) {
-    let report = ReportBuilder::default().add_testsuite(ts).build();
+fn write_junit_report(f: &Path, suites: Vec<TestSuite>) {
+    let report = ReportBuilder::default().add_testsuites(suites).build();
     let mut file = File::create(f).unwrap();
     report.write_xml(&mut file).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RunGauge;
+    use prometheus::core::Collector;
+
+    #[test]
+    fn publish_gauges_after_run() {
+        let gauge = RunGauge::new("git_mirror_test_published", "Test gauge", &["mirror"]);
+        let published = || gauge.published.with_label_values(&["a"]).get();
+
+        gauge.with_label_values(&["a"]).inc();
+        gauge.publish();
+        assert_eq!(published(), 1.0);
+
+        // The next run starts from scratch, the previous values stay published
+        gauge.run.reset();
+        gauge.with_label_values(&["b"]).inc();
+        assert_eq!(published(), 1.0);
+
+        gauge.publish();
+        assert_eq!(gauge.published.with_label_values(&["b"]).get(), 1.0);
+        // Only the values of the last run are published
+        assert_eq!(gauge.published.collect()[0].get_metric().len(), 1);
+    }
+}
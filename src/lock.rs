@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// File locking
+use fs2::FileExt;
+
+// Used to select the lock mode on the command line
+use clap::ValueEnum;
+
+// Time handling
+use time::OffsetDateTime;
+
+use crate::layout;
+
+/// How instances running against the same mirror directory are kept apart
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockMode {
+    /// Only one instance can use the mirror directory at a time
+    #[default]
+    Global,
+    /// Instances can run at the same time, each working repository is locked while it is synced
+    Repo,
+}
+
+// Lock of the whole mirror directory
+pub const MIRROR_DIR_LOCK: &str = "git-mirror.lock";
+
+// Interval to check if a contended lock was released
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Process holding a lock, recorded in the lock file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    pub host: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub since: OffsetDateTime,
+    /// Shared locks can have more holders, only the last one is recorded
+    pub exclusive: bool,
+}
+
+impl Holder {
+    fn current(exclusive: bool) -> Holder {
+        Holder {
+            pid: std::process::id(),
+            host: hostname(),
+            since: OffsetDateTime::now_utc(),
+            exclusive,
+        }
+    }
+
+    /// Whether the exclusive holder is known to be gone,
+    /// i.e. it ran on this host and isn't running anymore
+    pub fn is_stale(&self) -> bool {
+        self.exclusive && self.host == hostname() && !process_alive(self.pid)
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pid {} on {} since {}", self.pid, self.host, self.since)?;
+        if self.is_stale() {
+            write!(
+                f,
+                ", the process isn't running anymore, the lock might be stale"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A lock on a file, released when dropped
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    // Not truncated, the file names the current holder
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("Unable to open lockfile: {path:?} ({e})"))
+}
+
+/// Record the current process as holder of the lock
+fn record_holder(mut file: File, exclusive: bool) -> Lock {
+    let holder = serde_json::to_string(&Holder::current(exclusive)).unwrap_or_default();
+    // Only informational, the lock is held anyway
+    let _ = file
+        .set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| file.write_all(holder.as_bytes()));
+    Lock { _file: file }
+}
+
+/// Describe who holds the lock at `path`
+fn held_by(path: &Path) -> String {
+    match holder(path) {
+        Some(h) => format!("held by {h}"),
+        None => "held by an unknown process".to_string(),
+    }
+}
+
+/// Take the lock at `path` without waiting, shared locks can be held by several processes
+pub fn try_lock(path: &Path, exclusive: bool) -> Result<Lock, String> {
+    let file = open(path)?;
+    let locked = match exclusive {
+        true => FileExt::try_lock_exclusive(&file),
+        false => FileExt::try_lock_shared(&file),
+    };
+    match locked {
+        Ok(()) => Ok(record_holder(file, exclusive)),
+        Err(e) => Err(format!("{path:?} is {} ({e})", held_by(path))),
+    }
+}
+
+/// Take the exclusive lock at `path`, waiting at most `timeout` for it to be released
+pub fn lock(path: &Path, timeout: Duration) -> Result<Lock, String> {
+    let file = open(path)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => return Ok(record_holder(file, true)),
+            Err(_) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                return Err(format!(
+                    "{path:?} is still {} after waiting {timeout:?} ({e})",
+                    held_by(path)
+                ))
+            }
+        }
+    }
+}
+
+/// Last process that took the lock at `path`, it might not hold it anymore
+pub fn holder(path: &Path) -> Option<Holder> {
+    let mut content = String::new();
+    File::open(path).ok()?.read_to_string(&mut content).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Whether any process holds the lock at `path`
+pub fn is_held(path: &Path) -> bool {
+    match File::open(path) {
+        Ok(file) => FileExt::try_lock_exclusive(&file).is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Lock file of a working repository, next to the repository
+pub fn repo_lock_path(repo_dir: &Path) -> PathBuf {
+    let mut path = repo_dir.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// The existing lock files of the mirror directory and its working repositories
+pub fn find_locks(mirror_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut locks = vec![mirror_dir.join(MIRROR_DIR_LOCK)];
+    locks.extend(
+        layout::find_repos(mirror_dir)?
+            .iter()
+            .map(|r| repo_lock_path(r)),
+    );
+    locks.retain(|l| l.is_file());
+    Ok(locks)
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks if the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{holder, is_held, lock, try_lock};
+    use std::time::Duration;
+
+    #[test]
+    fn lock_and_report_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repo.lock");
+
+        let shared = try_lock(&path, false).unwrap();
+        let other = try_lock(&path, false).unwrap();
+        assert!(is_held(&path));
+        let e = lock(&path, Duration::from_millis(10)).unwrap_err();
+        assert!(e.contains(&format!("pid {}", std::process::id())), "{e}");
+
+        drop(shared);
+        drop(other);
+        assert!(!is_held(&path));
+        let _exclusive = lock(&path, Duration::from_millis(10)).unwrap();
+        let h = holder(&path).unwrap();
+        assert_eq!(h.pid, std::process::id());
+        assert!(h.exclusive);
+        assert!(!h.is_stale());
+        assert!(try_lock(&path, false).is_err());
+    }
+}
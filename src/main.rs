@@ -13,27 +13,116 @@ use log::{debug, error, info};
 
 // Used to do command line parsing
 use clap::{crate_name, crate_version};
-use clap::{ArgAction, Parser, ValueEnum};
-use std::path::PathBuf;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+// Used to read the sources file
+use serde_derive::Deserialize;
 
 // Load the real functionality
-use git_mirror::do_mirror;
-use git_mirror::provider::{GitHub, GitLab, Provider};
-use git_mirror::MirrorOptions;
+use git_mirror::guard::Threshold;
+use git_mirror::layout::Layout;
+use git_mirror::ledger::{Backoff, Ledger};
+use git_mirror::lock::{self, LockMode};
+use git_mirror::provider::{
+    AzureDevOps, BitbucketServer, File, GitHub, GitHubOwner, GitLab, Gitea, Provider,
+};
+use git_mirror::schedule::Schedule;
+use git_mirror::{do_mirror, migrate_layout, provision, prune, restore, run_daemon};
+use git_mirror::{GitBackend, MirrorOptions};
 
 use std::process::exit;
+use std::time::Duration;
+
+// Time handling
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
-#[derive(ValueEnum, Clone, Debug)]
+#[derive(ValueEnum, Deserialize, Clone, Debug)]
 #[value(rename_all = "verbatim")]
 enum Providers {
     GitLab,
     GitHub,
+    Gitea,
+    BitbucketServer,
+    AzureDevOps,
+    File,
+}
+
+impl Providers {
+    /// URL used if none is given on the command line
+    fn default_url(&self) -> Option<&'static str> {
+        match self {
+            Providers::GitLab => Some("https://gitlab.com"),
+            Providers::GitHub => Some("https://api.github.com"),
+            _ => None,
+        }
+    }
+}
+
+/// A provider and group to sync, as listed in the sources file
+#[derive(Deserialize, Debug)]
+struct Source {
+    provider: Providers,
+    url: Option<String>,
+    group: String,
+    /// Defaults to the `--http` command line flag
+    http: Option<bool>,
+    /// Defaults to the `--github-account` command line option
+    github_account: Option<GitHubOwner>,
+    /// Name of the environment variable holding the private token for this source,
+    /// defaults to the `--private-token` command line option
+    private_token_env: Option<String>,
+}
+
+/// Subcommands, without one the repositories are synced
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the outcome of the last sync of each repository in the mirror directory
+    Status {
+        /// Print the state as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Only show repositories whose last sync failed
+        #[arg(long)]
+        failing: bool,
+    },
+    /// Move the working repositories in the mirror directory to the layout given by `--layout`
+    Migrate,
+    /// Show the lock files in the mirror directory and which process holds them
+    Locks,
+    /// Remove the working repositories that don't belong to any mirror of the given groups or sources
+    Prune {
+        /// Move the working repositories into this directory instead of deleting them,
+        /// it has to be on the same file system as the mirror directory
+        #[arg(long)]
+        archive_dir: Option<PathBuf>,
+    },
+    /// List the backups of a destination's refs taken with `--backup-refs`, or restore one of them
+    Restore {
+        /// Destination URL as listed by the provider
+        destination: String,
+
+        /// Push the refs saved in the backup taken at this time back to the destination
+        #[arg(long)]
+        timestamp: Option<String>,
+    },
+    /// Create the destination projects listed in a manifest in the given group
+    Provision {
+        /// Manifest listing the origins to create projects for,
+        /// in YAML, JSON or TOML
+        manifest: PathBuf,
+    },
 }
 
 /// command line options
 #[derive(Parser, Debug)]
-#[command(name = "git-mirror", version, about)]
+#[command(name = "git-mirror", version, about, subcommand_negates_reqs = true)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Provider to use for fetching repositories
     #[arg(
         long = "provider",
@@ -45,34 +134,73 @@ struct Opt {
     provider: Providers,
 
     /// URL of the instance to get repositories from
+    /// (defaults to https://gitlab.com for GitLab and https://api.github.com for GitHub,
+    /// organization URL for Azure DevOps, e.g. https://dev.azure.com/my-org)
     #[arg(
         long = "url",
         short = 'u',
-        default_value_ifs([
-            ("provider", "GitLab", Some("https://gitlab.com")),
-            ("provider", "GitHub", Some("https://api.github.com")),
+        required_if_eq_any([
+            ("provider", "Gitea"),
+            ("provider", "BitbucketServer"),
+            ("provider", "AzureDevOps"),
         ])
     )]
-    url: String,
+    url: Option<String>,
 
     /// Name of the group to check for repositories to sync
-    #[arg(long = "group", short = 'g')]
-    group: String,
+    /// (organization for GitHub and Gitea, project key for Bitbucket Server,
+    /// project for Azure DevOps, path of the manifest for File)
+    /// Can be given multiple times to sync several groups in one run.
+    /// Not needed with `--github-account authenticated-user`.
+    #[arg(
+        long = "group",
+        short = 'g',
+        default_value_if("github_account", "authenticated-user", "")
+    )]
+    group: Vec<String>,
+
+    /// YAML file listing additional providers and groups to sync in the same run.
+    /// Each entry needs a `provider` and a `group` and can set `url`, `http`,
+    /// `github_account` and `private_token_env`.
+    #[arg(long)]
+    sources: Option<PathBuf>,
+
+    /// Kind of GitHub account the group refers to
+    #[arg(long, default_value = "org", value_enum)]
+    github_account: GitHubOwner,
 
     /// Directory where the local clones are stored
-    #[arg(long = "mirror-dir", short = 'm', default_value = "./mirror-dir")]
+    #[arg(
+        long = "mirror-dir",
+        short = 'm',
+        default_value = "./mirror-dir",
+        global = true
+    )]
     mirror_dir: PathBuf,
 
+    /// How the working repositories are named inside the mirror directory
+    #[arg(long, default_value = "slug", value_enum, global = true)]
+    layout: Layout,
+
+    /// How instances running against the same mirror directory are kept apart
+    #[arg(long, default_value = "global", value_enum)]
+    lock_mode: LockMode,
+
+    /// Seconds to wait for another sync of the same working repository
+    #[arg(long, default_value = "600")]
+    lock_timeout: u64,
+
     /// Verbosity level
     #[arg(short, long, action = ArgAction::Count)]
     verbose: u8,
 
-    /// Use http(s) instead of SSH to sync the GitLab repository
+    /// Use http(s) instead of SSH to sync the destination repository
     #[arg(long)]
     http: bool,
 
-    /// Only print what to do without actually running any git commands
-    #[arg(long)]
+    /// Only print what to do without fetching or pushing. With `--max-destructive-changes`
+    /// the refs of origin and destination are listed to check the limit.
+    #[arg(long, global = true)]
     dry_run: bool,
 
     /// Number of concurrent mirror jobs
@@ -84,15 +212,38 @@ struct Opt {
     #[arg(long)]
     metric_file: Option<PathBuf>,
 
+    /// Address to serve the metrics on via HTTP under `/metrics`,
+    /// together with `/healthz` and `/readyz`, e.g. "0.0.0.0:9090"
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Address to accept GitLab, GitHub and Gitea push webhooks on in daemon mode,
+    /// e.g. "0.0.0.0:8080". Webhooks are expected under `/webhook`.
+    #[arg(long, requires_all = ["daemon", "webhook_secret"])]
+    webhook_listen: Option<String>,
+
+    /// Secret token used to verify webhooks
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+
     /// Location where to store the Junit XML report
     #[arg(long)]
     junit_report: Option<PathBuf>,
 
     /// Git executable to use
-    #[arg(long, default_value = "git")]
+    #[arg(long, default_value = "git", global = true)]
     git_executable: String,
 
-    /// Private token or Personal access token to access the GitLab or GitHub API
+    /// Implementation used to run git operations
+    #[arg(long, default_value = "cli", value_enum)]
+    git_backend: GitBackend,
+
+    /// Token used by the libgit2 backend to authenticate to HTTPS remotes,
+    /// if no git credential helper provides credentials
+    #[arg(long, env = "GIT_TOKEN")]
+    git_token: Option<String>,
+
+    /// Private token or Personal access token to access the GitLab, GitHub, Gitea, Bitbucket Server or Azure DevOps API
     #[arg(long, env = "PRIVATE_TOKEN")]
     private_token: Option<String>,
 
@@ -111,25 +262,281 @@ struct Opt {
     /// Mirror lfs objects as well
     #[arg(long, default_value = "false")]
     lfs: bool,
+
+    /// Skip fetching and pushing repositories whose origin refs didn't change since the last push.
+    /// The refs are compared using `git ls-remote` against the state recorded in the working repository.
+    #[arg(long)]
+    skip_unchanged: bool,
+
+    /// After a sync, set the default branch of the destination project to the one of the origin
+    /// and apply `topics` and `archived` from the description (GitLab and GitHub)
+    #[arg(long)]
+    sync_metadata: bool,
+
+    /// Refuse to push if more than this number or percentage (e.g. `10%`) of the destination's refs
+    /// would be deleted or rewritten non-fast-forward. Can be overridden per repository
+    /// with `max_destructive_changes` in the description.
+    #[arg(long, value_name = "N|N%")]
+    max_destructive_changes: Option<Threshold>,
+
+    /// Before a push deletes or rewrites refs of the destination, save their previous tips
+    /// under `refs/git-mirror/backup/<timestamp>/` in `git-mirror-backup.git` in the mirror directory
+    #[arg(long)]
+    backup_refs: bool,
+
+    /// Push the saved refs to this remote as well
+    #[arg(long, requires = "backup_refs")]
+    backup_remote: Option<String>,
+
+    /// Seconds after which a hanging `git clone` is killed
+    #[arg(long)]
+    clone_timeout: Option<u64>,
+
+    /// Seconds after which a hanging fetch of updates or `git ls-remote` is killed
+    #[arg(long)]
+    update_timeout: Option<u64>,
+
+    /// Seconds after which a hanging `git push` is killed
+    #[arg(long)]
+    push_timeout: Option<u64>,
+
+    /// Seconds after which a hanging `git lfs fetch` is killed
+    #[arg(long)]
+    lfs_timeout: Option<u64>,
+
+    /// Number of times a git command is retried after a transient error,
+    /// e.g. a network timeout or a DNS failure
+    #[arg(long, default_value = "2")]
+    retries: u32,
+
+    /// Seconds to wait before retrying a git command
+    #[arg(long, default_value = "10")]
+    retry_delay: u64,
+
+    /// Number of consecutive failures after which a repository is only retried
+    /// on an exponential schedule, 0 disables the backoff
+    #[arg(long, default_value = "0")]
+    backoff_after: u32,
+
+    /// Seconds to wait before retrying a repository once it reached `--backoff-after` failures,
+    /// doubled with every further failure
+    #[arg(long, default_value = "3600")]
+    backoff_base: u64,
+
+    /// Longest time in seconds to wait before retrying a failing repository
+    #[arg(long, default_value = "86400")]
+    backoff_max: u64,
+
+    /// Keep running and repeat the sync according to `--interval` or `--cron`
+    #[arg(long)]
+    daemon: bool,
+
+    /// Seconds between the start of two sync runs in daemon mode
+    #[arg(
+        long,
+        default_value = "3600",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "cron",
+        requires = "daemon"
+    )]
+    interval: u64,
+
+    /// Cron expression (UTC) defining when to start a sync run in daemon mode,
+    /// e.g. "*/15 * * * *"
+    #[arg(long, requires = "daemon")]
+    cron: Option<String>,
 }
 
 impl From<Opt> for MirrorOptions {
     fn from(opt: Opt) -> MirrorOptions {
         MirrorOptions {
             mirror_dir: opt.mirror_dir,
+            layout: opt.layout,
+            lock_mode: opt.lock_mode,
+            lock_timeout: Duration::from_secs(opt.lock_timeout),
             dry_run: opt.dry_run,
             worker_count: opt.worker_count,
             metrics_file: opt.metric_file,
             junit_file: opt.junit_report,
             git_executable: opt.git_executable,
+            git_backend: opt.git_backend,
+            git_token: opt.git_token,
+            git: None,
             refspec: opt.refspec,
             remove_workrepo: opt.remove_workrepo,
             fail_on_sync_error: opt.fail_on_sync_error,
             mirror_lfs: opt.lfs,
+            skip_unchanged: opt.skip_unchanged,
+            sync_metadata: opt.sync_metadata,
+            max_destructive_changes: opt.max_destructive_changes,
+            backup_refs: opt.backup_refs,
+            backup_remote: opt.backup_remote,
+            clone_timeout: opt.clone_timeout.map(Duration::from_secs),
+            update_timeout: opt.update_timeout.map(Duration::from_secs),
+            push_timeout: opt.push_timeout.map(Duration::from_secs),
+            lfs_timeout: opt.lfs_timeout.map(Duration::from_secs),
+            retries: opt.retries,
+            retry_delay: Duration::from_secs(opt.retry_delay),
+            backoff: (opt.backoff_after > 0).then(|| Backoff {
+                after: opt.backoff_after,
+                base: Duration::from_secs(opt.backoff_base),
+                max: Duration::from_secs(opt.backoff_max),
+            }),
+            metrics_listen: opt.metrics_listen,
+            webhook_listen: opt.webhook_listen,
+            webhook_secret: opt.webhook_secret,
         }
     }
 }
 
+fn create_provider(
+    provider: &Providers,
+    url: Option<String>,
+    group: &str,
+    use_http: bool,
+    private_token: Option<String>,
+    github_account: GitHubOwner,
+) -> Result<Box<dyn Provider>, String> {
+    let url = match url.or_else(|| provider.default_url().map(String::from)) {
+        Some(url) => url,
+        None if matches!(provider, Providers::File) => String::new(),
+        None => return Err(format!("No URL given for {provider:?} group {group}")),
+    };
+    let provider: Box<dyn Provider> = match provider {
+        Providers::GitLab => Box::new(GitLab {
+            url,
+            group: group.to_owned(),
+            use_http,
+            private_token,
+            recursive: true,
+        }),
+        Providers::GitHub => Box::new(GitHub {
+            url,
+            org: group.to_owned(),
+            owner: github_account,
+            use_http,
+            private_token,
+            useragent: format!("{}/{}", crate_name!(), crate_version!()),
+        }),
+        Providers::Gitea => Box::new(Gitea {
+            url,
+            org: group.to_owned(),
+            use_http,
+            private_token,
+        }),
+        Providers::BitbucketServer => Box::new(BitbucketServer {
+            url,
+            project: group.to_owned(),
+            use_http,
+            private_token,
+        }),
+        Providers::AzureDevOps => Box::new(AzureDevOps {
+            url,
+            project: group.to_owned(),
+            use_http,
+            private_token,
+        }),
+        Providers::File => Box::new(File {
+            path: PathBuf::from(group),
+        }),
+    };
+    Ok(provider)
+}
+
+fn load_sources(path: &Path, opt: &Opt) -> Result<Vec<Box<dyn Provider>>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("Unable to open sources file: {path:?} ({e})"))?;
+    let sources: Vec<Source> = serde_yaml::from_reader(file)
+        .map_err(|e| format!("Unable to parse sources file: {path:?} ({e})"))?;
+
+    sources
+        .into_iter()
+        .map(|s| {
+            let private_token = match s.private_token_env {
+                Some(ref var) => Some(env::var(var).map_err(|e| {
+                    format!(
+                        "Unable to read private token from `{var}` for {} ({e})",
+                        s.group
+                    )
+                })?),
+                None => opt.private_token.clone(),
+            };
+            create_provider(
+                &s.provider,
+                s.url,
+                &s.group,
+                s.http.unwrap_or(opt.http),
+                private_token,
+                s.github_account.unwrap_or(opt.github_account),
+            )
+        })
+        .collect()
+}
+
+fn format_time(t: Option<OffsetDateTime>) -> String {
+    t.and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| "never".to_string())
+}
+
+/// Print the sync state recorded in the mirror directory
+fn print_status(mirror_dir: &Path, json: bool, failing: bool) -> Result<(), String> {
+    let mut ledger = Ledger::load(mirror_dir)?;
+    if failing {
+        ledger.repos.retain(|_, r| r.error.is_some());
+    }
+
+    if json {
+        let out = serde_json::to_string_pretty(&ledger)
+            .map_err(|e| format!("Unable to serialize state ({e})"))?;
+        println!("{out}");
+        return Ok(());
+    }
+
+    for (destination, r) in ledger.repos.iter() {
+        let result = match r.error {
+            Some(_) => "FAIL",
+            None => "OK",
+        };
+        println!("{result} {} -> {destination}", r.origin);
+        println!("    provider:             {}", r.label);
+        println!("    last attempt:         {}", format_time(r.last_attempt));
+        println!("    last success:         {}", format_time(r.last_success));
+        println!("    duration:             {:.1}s", r.duration_secs);
+        println!("    refs pushed:          {}", r.refs.len());
+        println!("    consecutive failures: {}", r.consecutive_failures);
+        if let Some(ref e) = r.error {
+            println!("    error:                {}", e.trim_end());
+        }
+    }
+    Ok(())
+}
+
+/// Print the lock files in the mirror directory together with their holders
+fn print_locks(mirror_dir: &Path) -> Result<(), String> {
+    for path in lock::find_locks(mirror_dir)? {
+        let held = lock::is_held(&path);
+        let holder = lock::holder(&path);
+        let state = match (held, holder.as_ref().map(|h| h.is_stale())) {
+            // The recorded process is gone, but something still holds the lock
+            (true, Some(true)) => "STALE",
+            (true, _) => "HELD",
+            (false, _) => "FREE",
+        };
+        println!("{state} {}", path.display());
+        if let Some(h) = holder {
+            println!(
+                "    {}: pid {} on {} since {}{}",
+                if held { "held by" } else { "last held by" },
+                h.pid,
+                h.host,
+                format_time(Some(h.since)),
+                if h.exclusive { "" } else { " (shared)" }
+            );
+        }
+    }
+    Ok(())
+}
+
 fn main() {
     // Setup commandline parser
     let opt = Opt::parse();
@@ -151,26 +558,120 @@ fn main() {
         openssl_probe::init_openssl_env_vars();
     };
 
-    let provider: Box<dyn Provider> = match opt.provider {
-        Providers::GitLab => Box::new(GitLab {
-            url: opt.url.to_owned(),
-            group: opt.group.to_owned(),
-            use_http: opt.http,
-            private_token: opt.private_token.to_owned(),
-            recursive: true,
-        }),
-        Providers::GitHub => Box::new(GitHub {
-            url: opt.url.to_owned(),
-            org: opt.group.to_owned(),
-            use_http: opt.http,
-            private_token: opt.private_token.to_owned(),
-            useragent: format!("{}/{}", crate_name!(), crate_version!()),
-        }),
+    match opt.command {
+        Some(Command::Status { json, failing }) => {
+            if let Err(e) = print_status(&opt.mirror_dir, json, failing) {
+                error!("{}", e);
+                exit(2);
+            }
+            return;
+        }
+        Some(Command::Locks) => {
+            if let Err(e) = print_locks(&opt.mirror_dir) {
+                error!("{}", e);
+                exit(2);
+            }
+            return;
+        }
+        Some(Command::Restore {
+            ref destination,
+            ref timestamp,
+        }) => {
+            let (destination, timestamp) = (destination.clone(), timestamp.clone());
+            if let Err(e) = restore(&destination, timestamp.as_deref(), &opt.into()) {
+                error!("{}", e);
+                exit(e.into());
+            }
+            return;
+        }
+        Some(Command::Migrate) => {
+            if let Err(e) = migrate_layout(&opt.into()) {
+                error!("{}", e);
+                exit(e.into());
+            }
+            return;
+        }
+        Some(Command::Prune { .. }) | Some(Command::Provision { .. }) | None => {}
+    }
+
+    let mut providers: Vec<Box<dyn Provider>> = Vec::new();
+    for group in opt.group.iter() {
+        match create_provider(
+            &opt.provider,
+            opt.url.clone(),
+            group,
+            opt.http,
+            opt.private_token.clone(),
+            opt.github_account,
+        ) {
+            Ok(p) => providers.push(p),
+            Err(e) => {
+                error!("{}", e);
+                exit(2);
+            }
+        }
+    }
+    if let Some(ref sources) = opt.sources {
+        match load_sources(sources, &opt) {
+            Ok(p) => providers.extend(p),
+            Err(e) => {
+                error!("{}", e);
+                exit(2);
+            }
+        }
+    }
+
+    if let Some(Command::Prune { ref archive_dir }) = opt.command {
+        if providers.is_empty() {
+            error!("A group or a sources file is required to know which repositories to keep");
+            exit(2);
+        }
+        let archive_dir = archive_dir.clone();
+        if let Err(e) = prune(providers, &opt.into(), archive_dir.as_deref()) {
+            error!("{}", e);
+            exit(e.into());
+        }
+        return;
+    }
+
+    if let Some(Command::Provision { ref manifest }) = opt.command {
+        if providers.len() != 1 {
+            error!("Exactly one group is required to know where to create the projects");
+            exit(2);
+        }
+        if let Err(e) = provision(providers[0].as_ref(), manifest, opt.dry_run) {
+            error!("{}", e);
+            exit(e.into());
+        }
+        return;
+    }
+
+    if providers.is_empty() {
+        error!("A group or a sources file is required");
+        exit(2);
+    }
+
+    let schedule = match opt.cron {
+        Some(ref c) => match Schedule::cron(c) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("{}", e);
+                exit(2);
+            }
+        },
+        None => Schedule::Interval(Duration::from_secs(opt.interval)),
     };
+    let daemon = opt.daemon;
 
     let opts: MirrorOptions = opt.into();
 
-    match do_mirror(provider, &opts) {
+    let result = if daemon {
+        run_daemon(providers, &opts, &schedule)
+    } else {
+        do_mirror(providers, &opts)
+    };
+
+    match result {
         Ok(_) => {
             info!("All done");
         }
@@ -0,0 +1,201 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::{debug, trace, warn};
+
+// Used for Azure DevOps API access via HTTPS
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::{StatusCode, Url};
+
+use crate::provider::{
+    check_status, parse_json, Desc, Mirror, MirrorError, MirrorResult, Provider,
+};
+
+/// Provider for Azure DevOps Repos
+///
+/// Azure Repos have no description field, so the mirror description
+/// is read from a `.git-mirror.yml` file in the default branch of each repository.
+#[derive(Debug)]
+pub struct AzureDevOps {
+    /// Organization URL, e.g. `https://dev.azure.com/my-org`
+    pub url: String,
+    pub project: String,
+    pub use_http: bool,
+    pub private_token: Option<String>,
+}
+
+// File in the default branch containing the mirror description
+const DESCRIPTION_FILE: &str = ".git-mirror.yml";
+
+// API version to request
+const API_VERSION: &str = "7.1";
+
+/// A list response from the Azure DevOps API
+#[derive(Deserialize, Debug)]
+struct List<T> {
+    value: Vec<T>,
+}
+
+/// A repository from the Azure DevOps API
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Repository {
+    id: String,
+    web_url: String,
+    remote_url: String,
+    ssh_url: String,
+    default_branch: Option<String>,
+    #[serde(default)]
+    is_disabled: bool,
+}
+
+/// A file from the Azure DevOps API
+#[derive(Deserialize, Debug)]
+struct Item {
+    #[serde(default)]
+    content: String,
+}
+
+impl AzureDevOps {
+    fn api_url(&self, path: &[&str]) -> Result<Url, String> {
+        let mut url =
+            Url::parse(&self.url).map_err(|e| format!("Invalid URL: {} ({e})", self.url))?;
+        url.path_segments_mut()
+            .map_err(|_| format!("Invalid URL: {}", self.url))?
+            .pop_if_empty()
+            .push(&self.project)
+            .push("_apis")
+            .extend(path);
+        url.query_pairs_mut()
+            .append_pair("api-version", API_VERSION);
+        Ok(url)
+    }
+
+    fn authenticated(&self, req: RequestBuilder) -> RequestBuilder {
+        // Personal access tokens are sent as password with an empty user name
+        match self.private_token {
+            Some(ref token) => req.basic_auth("", Some(token)),
+            None => req,
+        }
+    }
+
+    fn get_repositories(&self, client: &Client) -> Result<Vec<Repository>, String> {
+        let url = self.api_url(&["git", "repositories"])?;
+        trace!("URL: {}", url);
+
+        let res = self
+            .authenticated(client.get(url.clone()))
+            .send()
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+
+        debug!("HTTP Status Received: {}", res.status());
+
+        let list: List<Repository> = parse_json(check_status(res, url.as_str())?)?;
+
+        Ok(list.value)
+    }
+
+    /// Get the mirror description of a repository.
+    /// Returns an empty description if the file does not exist.
+    fn get_description(&self, repo: &Repository, client: &Client) -> Result<String, String> {
+        if repo.default_branch.is_none() {
+            trace!("Repository {} is empty", repo.web_url);
+            return Ok(String::new());
+        }
+
+        let mut url = self.api_url(&["git", "repositories", &repo.id, "items"])?;
+        url.query_pairs_mut()
+            .append_pair("path", DESCRIPTION_FILE)
+            .append_pair("includeContent", "true")
+            .append_pair("$format", "json");
+        trace!("URL: {}", url);
+
+        let res = self
+            .authenticated(client.get(url.clone()))
+            .send()
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+
+        debug!("HTTP Status Received: {}", res.status());
+
+        match res.status() {
+            StatusCode::NOT_FOUND => {
+                trace!("No {} in {}", DESCRIPTION_FILE, repo.web_url);
+                Ok(String::new())
+            }
+            _ => {
+                let item: Item = parse_json(check_status(res, url.as_str())?)?;
+                Ok(item.content)
+            }
+        }
+    }
+}
+
+impl Provider for AzureDevOps {
+    fn get_label(&self) -> String {
+        format!("{}/{}", self.url, self.project)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let client = Client::new();
+
+        let use_http = self.use_http;
+
+        if self.private_token.is_none() {
+            warn!("PRIVATE_TOKEN not set")
+        }
+
+        let repositories = self.get_repositories(&client)?;
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for r in repositories {
+            let destination = match use_http {
+                true => r.remote_url.clone(),
+                false => r.ssh_url.clone(),
+            };
+            if r.is_disabled {
+                mirrors.push(Err(MirrorError::Skip(r.web_url, None, Some(destination))));
+                continue;
+            }
+            let description = match self.get_description(&r, &client) {
+                Ok(d) => d,
+                Err(e) => {
+                    mirrors.push(Err(MirrorError::Provider(r.web_url, e)));
+                    continue;
+                }
+            };
+            match serde_yaml::from_str::<Desc>(&description) {
+                Ok(desc) => {
+                    if desc.skip {
+                        mirrors.push(Err(MirrorError::Skip(
+                            r.web_url,
+                            Some(desc.origin),
+                            Some(destination),
+                        )));
+                        continue;
+                    }
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    let m = Mirror {
+                        origin: desc.origin,
+                        destination,
+                        refspec: desc.refspec,
+                        lfs: desc.lfs,
+                        topics: desc.topics,
+                        archived: desc.archived,
+                        max_destructive_changes: desc.max_destructive_changes,
+                    };
+                    mirrors.push(Ok(m));
+                }
+                Err(e) => {
+                    mirrors.push(Err(MirrorError::Description(r.web_url, e)));
+                }
+            }
+        }
+
+        Ok(mirrors)
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::{error, trace, warn};
+
+// Used for Bitbucket API access via HTTPS
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+use crate::provider::{
+    get, get_paged, parse_json, Desc, Mirror, MirrorError, MirrorResult, Provider,
+};
+
+/// Provider for Bitbucket Server and Bitbucket Data Center
+#[derive(Debug)]
+pub struct BitbucketServer {
+    pub url: String,
+    pub project: String,
+    pub use_http: bool,
+    pub private_token: Option<String>,
+}
+
+/// A page of results from the Bitbucket Server API
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Page<T> {
+    values: Vec<T>,
+    is_last_page: bool,
+    next_page_start: Option<u32>,
+}
+
+/// A link from the Bitbucket Server API
+#[derive(Deserialize, Debug, Clone)]
+struct Link {
+    href: String,
+    name: Option<String>,
+}
+
+/// The links of a repository from the Bitbucket Server API
+#[derive(Deserialize, Debug, Clone)]
+struct Links {
+    #[serde(default)]
+    clone: Vec<Link>,
+    #[serde(rename = "self", default)]
+    web: Vec<Link>,
+}
+
+/// A repository from the Bitbucket Server API
+#[derive(Deserialize, Debug, Clone)]
+struct Repository {
+    slug: String,
+    description: Option<String>,
+    links: Links,
+}
+
+impl Repository {
+    fn web_url(&self) -> String {
+        self.links
+            .web
+            .first()
+            .map(|l| l.href.clone())
+            .unwrap_or_else(|| self.slug.clone())
+    }
+
+    fn clone_url(&self, name: &str) -> Option<String> {
+        self.links
+            .clone
+            .iter()
+            .find(|l| l.name.as_deref() == Some(name))
+            .map(|l| l.href.clone())
+    }
+}
+
+// Number of items per page to request
+const PER_PAGE: u8 = 100;
+
+impl BitbucketServer {
+    fn get_paged<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> Result<Vec<T>, String> {
+        get_paged(
+            &format!("{url}?limit={PER_PAGE}&start=0"),
+            |page_url| get(page_url, client, headers),
+            |res, _| {
+                let page: Page<T> = parse_json(res)?;
+                let next = match page.next_page_start {
+                    Some(next) if !page.is_last_page => {
+                        trace!("Next page starts at: {}", next);
+                        Some(format!("{url}?limit={PER_PAGE}&start={next}"))
+                    }
+                    _ => {
+                        trace!("No more pages, isLastPage set.");
+                        None
+                    }
+                };
+                Ok((page.values, next))
+            },
+        )
+    }
+}
+
+impl Provider for BitbucketServer {
+    fn get_label(&self) -> String {
+        format!("{}/projects/{}", self.url, self.project)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let client = Client::new();
+
+        let use_http = self.use_http;
+
+        let mut headers = HeaderMap::new();
+        if let Some(ref token) = self.private_token {
+            match HeaderValue::from_str(&format!("Bearer {token}")) {
+                Ok(token) => {
+                    headers.insert(AUTHORIZATION, token);
+                }
+                Err(err) => {
+                    error!("Unable to parse PRIVATE_TOKEN: {}", err);
+                }
+            }
+        } else {
+            warn!("PRIVATE_TOKEN not set")
+        }
+
+        let url = format!("{}/rest/api/1.0/projects/{}/repos", self.url, self.project);
+
+        let repositories = self.get_paged::<Repository>(&url, &client, &headers)?;
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for r in repositories {
+            let web_url = r.web_url();
+            match serde_yaml::from_str::<Desc>(&r.description.clone().unwrap_or_default()) {
+                Ok(desc) => {
+                    let destination = if use_http {
+                        r.clone_url("http")
+                    } else {
+                        r.clone_url("ssh")
+                    };
+                    if desc.skip {
+                        mirrors.push(Err(MirrorError::Skip(
+                            web_url,
+                            Some(desc.origin),
+                            destination,
+                        )));
+                        continue;
+                    }
+                    let destination = match destination {
+                        Some(d) => d,
+                        None => {
+                            let e = format!(
+                                "No {} clone link found",
+                                if use_http { "http" } else { "ssh" }
+                            );
+                            mirrors.push(Err(MirrorError::Provider(web_url, e)));
+                            continue;
+                        }
+                    };
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    let m = Mirror {
+                        origin: desc.origin,
+                        destination,
+                        refspec: desc.refspec,
+                        lfs: desc.lfs,
+                        topics: desc.topics,
+                        archived: desc.archived,
+                        max_destructive_changes: desc.max_destructive_changes,
+                    };
+                    mirrors.push(Ok(m));
+                }
+                Err(e) => {
+                    mirrors.push(Err(MirrorError::Description(web_url, e)));
+                }
+            }
+        }
+
+        Ok(mirrors)
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::PathBuf;
+
+// Used for error and debug logging
+use log::trace;
+
+use crate::guard::Threshold;
+use crate::provider::{bool_true, parse_manifest, Mirror, MirrorError, MirrorResult, Provider};
+
+/// Provider reading the mirror list from a local manifest file
+///
+/// The manifest can be written in YAML, JSON or TOML, the format is
+/// selected by the file extension. YAML is used for unknown extensions.
+#[derive(Debug)]
+pub struct File {
+    pub path: PathBuf,
+}
+
+/// A single mirror entry in the manifest
+#[derive(Deserialize, Debug)]
+struct Entry {
+    origin: String,
+    destination: String,
+    #[serde(default)]
+    skip: bool,
+    refspec: Option<Vec<String>>,
+    #[serde(default = "bool_true")]
+    lfs: bool,
+    max_destructive_changes: Option<Threshold>,
+}
+
+/// The manifest, either a plain list or a map with a `mirrors` list
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Manifest {
+    List(Vec<Entry>),
+    Map { mirrors: Vec<Entry> },
+}
+
+impl Manifest {
+    fn entries(self) -> Vec<Entry> {
+        match self {
+            Manifest::List(mirrors) | Manifest::Map { mirrors } => mirrors,
+        }
+    }
+}
+
+impl Provider for File {
+    fn get_label(&self) -> String {
+        format!("file://{}", self.path.display())
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        trace!("Manifest: {:?}", self.path);
+
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Unable to read manifest: {:?} ({e})", self.path))?;
+
+        let extension = self.path.extension().and_then(|e| e.to_str());
+        let manifest = parse_manifest::<Manifest>(&content, extension)
+            .map_err(|e| format!("Unable to parse manifest: {:?} ({e})", self.path))?;
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for e in manifest.entries() {
+            if e.skip {
+                mirrors.push(Err(MirrorError::Skip(
+                    e.destination.clone(),
+                    Some(e.origin),
+                    Some(e.destination),
+                )));
+                continue;
+            }
+            trace!("{0} -> {1}", e.origin, e.destination);
+            let m = Mirror {
+                origin: e.origin,
+                destination: e.destination,
+                refspec: e.refspec,
+                lfs: e.lfs,
+                topics: None,
+                archived: false,
+                max_destructive_changes: e.max_destructive_changes,
+            };
+            mirrors.push(Ok(m));
+        }
+
+        Ok(mirrors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_manifest, Manifest};
+
+    #[test]
+    fn parse_formats() {
+        let yaml = "
+- origin: https://example.org/a.git
+  destination: /srv/git/a.git
+- origin: https://example.org/b.git
+  destination: /srv/git/b.git
+  refspec: [main]
+  lfs: false
+";
+        let toml = r#"
+[[mirrors]]
+origin = "https://example.org/a.git"
+destination = "/srv/git/a.git"
+skip = true
+"#;
+        let json = r#"{"mirrors": [{"origin": "o", "destination": "d"}]}"#;
+
+        let entries = parse_manifest::<Manifest>(yaml, Some("yml"))
+            .unwrap()
+            .entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].lfs);
+        assert!(!entries[1].lfs);
+        assert_eq!(entries[1].refspec, Some(vec!["main".to_string()]));
+
+        let entries = parse_manifest::<Manifest>(toml, Some("toml"))
+            .unwrap()
+            .entries();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].skip);
+
+        let entries = parse_manifest::<Manifest>(json, Some("json"))
+            .unwrap()
+            .entries();
+        assert_eq!(entries[0].destination, "d");
+
+        assert!(parse_manifest::<Manifest>("- origin: o", None).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::{error, trace, warn};
+
+// Used for Gitea/Forgejo API access via HTTPS
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+use crate::provider::{
+    get, get_paged, parse_json, Desc, Mirror, MirrorError, MirrorResult, Provider,
+};
+
+/// Provider for Gitea and Forgejo instances
+#[derive(Debug)]
+pub struct Gitea {
+    pub url: String,
+    pub org: String,
+    pub use_http: bool,
+    pub private_token: Option<String>,
+}
+
+/// A repository from the Gitea API
+#[derive(Deserialize, Debug, Clone)]
+struct Repository {
+    #[serde(default)]
+    description: String,
+    html_url: String,
+    ssh_url: String,
+    clone_url: String,
+}
+
+// Number of items per page to request, Gitea caps this at 50 by default
+const PER_PAGE: u8 = 50;
+
+impl Gitea {
+    fn get_paged<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> Result<Vec<T>, String> {
+        let mut page = 1;
+        get_paged(
+            &format!("{url}?limit={PER_PAGE}&page={page}"),
+            |page_url| get(page_url, client, headers),
+            |res, received| {
+                // The server may use a smaller page size than requested,
+                // so rely on the total count instead of the page length.
+                let total = res
+                    .headers()
+                    .get("x-total-count")
+                    .and_then(|n| n.to_str().ok())
+                    .and_then(|n| n.parse::<usize>().ok());
+
+                let results_page: Vec<T> = parse_json(res)?;
+                let received = received + results_page.len();
+
+                let has_next = if results_page.is_empty() {
+                    trace!("No more pages, empty page received.");
+                    false
+                } else {
+                    match total {
+                        Some(total) if received >= total => {
+                            trace!("No more pages, received all {} items.", total);
+                            false
+                        }
+                        Some(total) => {
+                            trace!("Received {}/{} items.", received, total);
+                            true
+                        }
+                        None => {
+                            trace!("No x-total-count header, requesting next page.");
+                            true
+                        }
+                    }
+                };
+                page += 1;
+                let next = has_next.then(|| format!("{url}?limit={PER_PAGE}&page={page}"));
+                Ok((results_page, next))
+            },
+        )
+    }
+}
+
+impl Provider for Gitea {
+    fn get_label(&self) -> String {
+        format!("{}/{}", self.url, self.org)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let client = Client::new();
+
+        let use_http = self.use_http;
+
+        let mut headers = HeaderMap::new();
+        if let Some(ref token) = self.private_token {
+            match HeaderValue::from_str(&format!("token {token}")) {
+                Ok(token) => {
+                    headers.insert(AUTHORIZATION, token);
+                }
+                Err(err) => {
+                    error!("Unable to parse PRIVATE_TOKEN: {}", err);
+                }
+            }
+        } else {
+            warn!("PRIVATE_TOKEN not set")
+        }
+
+        let url = format!("{}/api/v1/orgs/{}/repos", self.url, self.org);
+
+        let repositories = self.get_paged::<Repository>(&url, &client, &headers)?;
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for r in repositories {
+            match serde_yaml::from_str::<Desc>(&r.description) {
+                Ok(desc) => {
+                    let destination = if use_http { r.clone_url } else { r.ssh_url };
+                    if desc.skip {
+                        mirrors.push(Err(MirrorError::Skip(
+                            r.html_url,
+                            Some(desc.origin),
+                            Some(destination),
+                        )));
+                        continue;
+                    }
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    let m = Mirror {
+                        origin: desc.origin,
+                        destination,
+                        refspec: desc.refspec,
+                        lfs: desc.lfs,
+                        topics: desc.topics,
+                        archived: desc.archived,
+                        max_destructive_changes: desc.max_destructive_changes,
+                    };
+                    mirrors.push(Ok(m));
+                }
+                Err(e) => {
+                    mirrors.push(Err(MirrorError::Description(r.html_url, e)));
+                }
+            }
+        }
+
+        Ok(mirrors)
+    }
+}
@@ -5,18 +5,46 @@
  */
 
 // Used for error and debug logging
-use log::trace;
+use log::{debug, error, trace, warn};
+
+use std::thread;
+use std::time::Duration;
 
 // Used for github API access via HTTPS
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, USER_AGENT};
-use reqwest::StatusCode;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, LINK, RETRY_AFTER, USER_AGENT,
+};
+use reqwest::{Method, StatusCode};
+
+// Time handling
+use time::OffsetDateTime;
 
-use crate::provider::{Desc, Mirror, MirrorError, MirrorResult, Provider};
+// Used to select the kind of account on the command line
+use clap::ValueEnum;
+
+use crate::provider::{
+    check_status, get_paged, parse_json, project_path, Desc, Metadata, Mirror, MirrorError,
+    MirrorResult, NewProject, Provider, Visibility,
+};
+
+/// The kind of account whose repositories are mirrored
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitHubOwner {
+    /// The group (`org`) is an organization
+    Org,
+    /// The group (`org`) is a user
+    User,
+    /// Use the repositories of the user owning the private token, the group (`org`) is ignored
+    AuthenticatedUser,
+}
 
 pub struct GitHub {
     pub url: String,
+    /// Name of the organization or user
     pub org: String,
+    pub owner: GitHubOwner,
     pub use_http: bool,
     pub private_token: Option<String>,
     pub useragent: String,
@@ -26,21 +54,96 @@ pub struct GitHub {
 #[derive(Deserialize, Debug)]
 struct Project {
     description: Option<String>,
+    #[serde(default)]
+    archived: bool,
     url: String,
     ssh_url: String,
     clone_url: String,
 }
 
-impl Provider for GitHub {
-    fn get_label(&self) -> String {
-        format!("{}/orgs/{}", self.url, self.org)
+/// A newly created repository from the GitHub API
+#[derive(Deserialize, Debug)]
+struct CreatedProject {
+    html_url: String,
+}
+
+/// The user the token belongs to from the GitHub API
+#[derive(Deserialize, Debug)]
+struct User {
+    login: String,
+}
+
+// Number of items per page to request
+const PER_PAGE: u8 = 100;
+
+// Number of times a request is retried after hitting a rate limit
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+// Longest time to wait for a rate limit to reset
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60 * 60);
+
+// Time to wait for a secondary rate limit without `Retry-After` header
+const SECONDARY_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Get the time to wait before retrying a request that hit a rate limit.
+/// Returns `None` if the response is not caused by a rate limit.
+fn rate_limit_wait(
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &str,
+    now: i64,
+) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
     }
 
-    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
-        let client = Client::new();
+    let header = |name: &str| -> Option<i64> { headers.get(name)?.to_str().ok()?.parse().ok() };
 
-        let use_http = self.use_http;
+    // Secondary rate limits tell how long to wait
+    if let Some(secs) = header(RETRY_AFTER.as_str()) {
+        return Some(Duration::from_secs(secs.max(0) as u64));
+    }
+
+    // Primary rate limits tell when the limit resets
+    if header("x-ratelimit-remaining") == Some(0) {
+        if let Some(reset) = header("x-ratelimit-reset") {
+            return Some(Duration::from_secs((reset - now).max(0) as u64 + 1));
+        }
+    }
 
+    // Secondary rate limits without `Retry-After` are only told apart
+    // from permission problems by their message
+    let body = body.to_lowercase();
+    if status == StatusCode::TOO_MANY_REQUESTS
+        || body.contains("secondary rate limit")
+        || body.contains("abuse detection")
+    {
+        Some(SECONDARY_RATE_LIMIT_WAIT)
+    } else {
+        None
+    }
+}
+
+/// Extract the URL of the next page from a `Link` header
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+    link.split(',').find_map(|l| {
+        let (url, params) = l.split_once(';')?;
+        if params.split(';').any(|p| p.trim() == r#"rel="next""#) {
+            Some(
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+impl GitHub {
+    fn headers(&self) -> HeaderMap {
         let mut headers = HeaderMap::new();
         // Github rejects requests without user agent
         let useragent = HeaderValue::from_str(&self.useragent).expect("User agent invalid!");
@@ -48,53 +151,176 @@ impl Provider for GitHub {
         // Set the accept header to make sure the v3 api is used
         let accept = HeaderValue::from_static("application/vnd.github.v3+json");
         headers.insert(ACCEPT, accept);
+        // Classic and fine-grained personal access tokens as well as
+        // GitHub App installation tokens are all sent as bearer token
+        if let Some(ref token) = self.private_token {
+            match HeaderValue::from_str(&format!("Bearer {token}")) {
+                Ok(mut token) => {
+                    token.set_sensitive(true);
+                    headers.insert(AUTHORIZATION, token);
+                }
+                Err(err) => {
+                    error!("Unable to parse PRIVATE_TOKEN: {}", err);
+                }
+            }
+        }
+        headers
+    }
+
+    fn repos_url(&self) -> String {
+        match self.owner {
+            GitHubOwner::Org => format!("{}/orgs/{}/repos?per_page={PER_PAGE}", self.url, self.org),
+            GitHubOwner::User => {
+                format!("{}/users/{}/repos?per_page={PER_PAGE}", self.url, self.org)
+            }
+            GitHubOwner::AuthenticatedUser => format!(
+                "{}/user/repos?per_page={PER_PAGE}&affiliation=owner",
+                self.url
+            ),
+        }
+    }
 
-        let url = format!("{}/orgs/{}/repos", self.url, self.org);
+    /// Send `body` as JSON and parse the response
+    fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        method: Method,
+        url: &str,
+        body: &serde_json::Value,
+        client: &Client,
+    ) -> Result<T, String> {
         trace!("URL: {}", url);
 
         let res = client
-            .get(&url)
-            .headers(headers)
+            .request(method, url)
+            .headers(self.headers())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
             .send()
             .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
 
-        if res.status() != StatusCode::OK {
-            if res.status() == StatusCode::UNAUTHORIZED {
+        debug!("HTTP Status Received: {}", res.status());
+
+        if !res.status().is_success() {
+            let status = res.status();
+            return Err(format!(
+                "API call received invalid status ({}) for : {} ({})",
+                status,
+                url,
+                res.text().unwrap_or_default()
+            ));
+        }
+
+        parse_json(res)
+    }
+
+    /// Send a GET request to `url`, waiting and retrying while it hits a rate limit
+    fn get(&self, url: &str, client: &Client, headers: &HeaderMap) -> Result<Response, String> {
+        let mut retries = 0;
+        loop {
+            trace!("URL: {}", url);
+
+            let res = client
+                .get(url)
+                .headers(headers.clone())
+                .send()
+                .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+
+            debug!("HTTP Status Received: {}", res.status());
+
+            let status = res.status();
+            if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+                return check_status(res, url);
+            }
+
+            let headers = res.headers().clone();
+            let body = res.text().unwrap_or_default();
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let Some(wait) = rate_limit_wait(status, &headers, &body, now) else {
                 return Err(format!(
-                    "API call received unautorized ({}) for: {}. \
-                     Please make sure the `GITHUB_PRIVATE_TOKEN` environment \
-                     variable is set.",
-                    res.status(),
-                    url
+                    "API call received invalid status ({status}) for : {url} ({body})"
                 ));
-            } else {
+            };
+            if retries >= MAX_RATE_LIMIT_RETRIES || wait > MAX_RATE_LIMIT_WAIT {
                 return Err(format!(
-                    "API call received invalid status ({}) for : {}",
-                    res.status(),
-                    url
+                    "API call hit the rate limit ({status}) for: {url}, giving up after {retries} retries"
                 ));
             }
+            retries += 1;
+            warn!(
+                "API call hit the rate limit ({}) for: {}, retrying in {}s",
+                status,
+                url,
+                wait.as_secs()
+            );
+            thread::sleep(wait);
         }
+    }
 
-        let projects: Vec<Project> = serde_json::from_reader(res)
-            .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+    fn get_paged<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> Result<Vec<T>, String> {
+        get_paged(
+            url,
+            |page_url| self.get(page_url, client, headers),
+            |res, _| {
+                let next = next_link(res.headers());
+                match next {
+                    Some(ref n) => trace!("Next page: {}", n),
+                    None => trace!("No more pages, no next link."),
+                }
+                Ok((parse_json(res)?, next))
+            },
+        )
+    }
+}
+
+impl Provider for GitHub {
+    fn get_label(&self) -> String {
+        match self.owner {
+            GitHubOwner::Org => format!("{}/orgs/{}", self.url, self.org),
+            GitHubOwner::User => format!("{}/users/{}", self.url, self.org),
+            GitHubOwner::AuthenticatedUser => format!("{}/user", self.url),
+        }
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let client = Client::new();
+
+        let use_http = self.use_http;
+
+        let headers = self.headers();
+        if self.private_token.is_none() {
+            warn!("PRIVATE_TOKEN not set, only public repositories are listed")
+        }
+
+        let projects = self.get_paged::<Project>(&self.repos_url(), &client, &headers)?;
 
         let mut mirrors: Vec<MirrorResult> = Vec::new();
 
         for p in projects {
             match serde_yaml::from_str::<Desc>(&p.description.unwrap_or_default()) {
                 Ok(desc) => {
-                    if desc.skip {
-                        mirrors.push(Err(MirrorError::Skip(p.url)));
+                    let destination = if use_http { p.clone_url } else { p.ssh_url };
+                    if desc.skip || (desc.archived && p.archived) {
+                        mirrors.push(Err(MirrorError::Skip(
+                            p.url,
+                            Some(desc.origin),
+                            Some(destination),
+                        )));
                         continue;
                     }
-                    trace!("{0} -> {1}", desc.origin, p.ssh_url);
-                    let destination = if use_http { p.clone_url } else { p.ssh_url };
+                    trace!("{0} -> {1}", desc.origin, destination);
                     let m = Mirror {
                         origin: desc.origin,
                         destination,
                         refspec: desc.refspec,
                         lfs: desc.lfs,
+                        topics: desc.topics,
+                        archived: desc.archived,
+                        max_destructive_changes: desc.max_destructive_changes,
                     };
                     mirrors.push(Ok(m));
                 }
@@ -105,4 +331,141 @@ impl Provider for GitHub {
         }
         Ok(mirrors)
     }
+
+    fn create_project(&self, project: &NewProject) -> Result<String, String> {
+        if let Some(ref namespace) = project.namespace {
+            return Err(format!(
+                "Unable to create {} in {namespace}, GitHub doesn't support namespaces",
+                project.name
+            ));
+        }
+
+        let mut body = serde_json::json!({
+            "name": project.name,
+            "description": project.description(),
+            "private": project.visibility != Visibility::Public,
+        });
+        let client = Client::new();
+        // Repositories of users are created for the user the token belongs to
+        let url = match self.owner {
+            GitHubOwner::Org => {
+                // Internal repositories only exist in organizations
+                body["visibility"] = project.visibility.as_str().into();
+                format!("{}/orgs/{}/repos", self.url, self.org)
+            }
+            GitHubOwner::User => {
+                let url = format!("{}/user", self.url);
+                let user: User = parse_json(self.get(&url, &client, &self.headers())?)?;
+                // Logins are case insensitive
+                if !user.login.eq_ignore_ascii_case(&self.org) {
+                    return Err(format!(
+                        "Unable to create {} for {}, the token belongs to {}",
+                        project.name, self.org, user.login
+                    ));
+                }
+                format!("{}/user/repos", self.url)
+            }
+            GitHubOwner::AuthenticatedUser => format!("{}/user/repos", self.url),
+        };
+        let created: CreatedProject = self.send(Method::POST, &url, &body, &client)?;
+        Ok(created.html_url)
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    fn update_metadata(&self, mirror: &Mirror, metadata: &Metadata) -> Result<(), String> {
+        let client = Client::new();
+        let url = format!("{}/repos/{}", self.url, project_path(&mirror.destination));
+
+        if let Some(ref branch) = metadata.default_branch {
+            let body = serde_json::json!({ "default_branch": branch });
+            self.send::<serde_json::Value>(Method::PATCH, &url, &body, &client)?;
+        }
+        if let Some(ref topics) = metadata.topics {
+            let body = serde_json::json!({ "names": topics });
+            let url = format!("{url}/topics");
+            self.send::<serde_json::Value>(Method::PUT, &url, &body, &client)?;
+        }
+        // Last, archived repositories can't be changed anymore
+        if metadata.archived {
+            let body = serde_json::json!({ "archived": true });
+            self.send::<serde_json::Value>(Method::PATCH, &url, &body, &client)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_link, rate_limit_wait, SECONDARY_RATE_LIMIT_WAIT};
+    use reqwest::header::{HeaderMap, HeaderValue, LINK, RETRY_AFTER};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn detect_rate_limit() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(rate_limit_wait(StatusCode::OK, &headers, "", 0), None);
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &headers, "", 0),
+            None
+        );
+        assert_eq!(
+            rate_limit_wait(StatusCode::TOO_MANY_REQUESTS, &headers, "", 0),
+            Some(SECONDARY_RATE_LIMIT_WAIT)
+        );
+
+        // Secondary rate limit without `Retry-After`
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("4000"));
+        let body = r#"{"message": "You have exceeded a secondary rate limit. Please wait a few minutes before you try again."}"#;
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &headers, body, 0),
+            Some(SECONDARY_RATE_LIMIT_WAIT)
+        );
+        let body = r#"{"message": "Resource not accessible by integration"}"#;
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &headers, body, 0),
+            None
+        );
+
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1100"));
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &headers, "", 1000),
+            Some(Duration::from_secs(101))
+        );
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(
+            rate_limit_wait(StatusCode::FORBIDDEN, &headers, "", 1000),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn parse_link_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(next_link(&headers), None);
+
+        headers.insert(
+            LINK,
+            HeaderValue::from_static(
+                r#"<https://api.github.com/organizations/1/repos?per_page=100&page=2>; rel="next", <https://api.github.com/organizations/1/repos?per_page=100&page=5>; rel="last""#,
+            ),
+        );
+        assert_eq!(
+            next_link(&headers).as_deref(),
+            Some("https://api.github.com/organizations/1/repos?per_page=100&page=2")
+        );
+
+        headers.insert(
+            LINK,
+            HeaderValue::from_static(
+                r#"<https://api.github.com/organizations/1/repos?per_page=100&page=1>; rel="prev", <https://api.github.com/organizations/1/repos?per_page=100&page=1>; rel="first""#,
+            ),
+        );
+        assert_eq!(next_link(&headers), None);
+    }
 }
@@ -5,13 +5,16 @@
  */
 
 // Used for error and debug logging
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 
 use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
 
-use crate::provider::{Desc, Mirror, MirrorError, MirrorResult, Provider};
+use crate::provider::{
+    get, get_paged, parse_json, project_path, Desc, Metadata, Mirror, MirrorError, MirrorResult,
+    NewProject, Provider,
+};
 
 #[derive(Debug)]
 pub struct GitLab {
@@ -26,6 +29,8 @@ pub struct GitLab {
 #[derive(Deserialize, Debug, Clone)]
 struct Project {
     description: String,
+    #[serde(default)]
+    archived: bool,
     web_url: String,
     ssh_url_to_repo: String,
     http_url_to_repo: String,
@@ -37,74 +42,137 @@ struct Group {
     id: u64,
 }
 
+/// A newly created project from the GitLab API
+#[derive(Deserialize, Debug)]
+struct CreatedProject {
+    web_url: String,
+}
+
+/// Encode a group or project path to be used in place of its id
+fn encode_path(path: &str) -> String {
+    path.replace('/', "%2F")
+}
+
 // Number of items per page to request
 const PER_PAGE: u8 = 100;
 
 impl GitLab {
-    fn get_paged<T: serde::de::DeserializeOwned>(
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(ref token) = self.private_token {
+            match HeaderValue::from_str(token) {
+                Ok(token) => {
+                    headers.insert("PRIVATE-TOKEN", token);
+                }
+                Err(err) => {
+                    error!("Unable to parse PRIVATE_TOKEN: {}", err);
+                }
+            }
+        } else {
+            warn!("PRIVATE_TOKEN not set")
+        }
+        headers
+    }
+
+    /// Send `body` as JSON and parse the response
+    fn send<T: serde::de::DeserializeOwned>(
         &self,
+        method: Method,
         url: &str,
+        body: &serde_json::Value,
         client: &Client,
         headers: &HeaderMap,
-    ) -> Result<Vec<T>, String> {
-        let mut results: Vec<T> = Vec::new();
-
-        for page in 1..u32::MAX {
-            let url = format!("{url}?per_page={PER_PAGE}&page={page}");
-            trace!("URL: {}", url);
-
-            let res = client
-                .get(&url)
-                .headers(headers.clone())
-                .send()
-                .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
-
-            debug!("HTTP Status Received: {}", res.status());
-
-            if res.status() != StatusCode::OK {
-                if res.status() == StatusCode::UNAUTHORIZED {
-                    return Err(format!(
-                        "API call received unautorized ({}) for: {}. \
-                         Please make sure the `GITLAB_PRIVATE_TOKEN` environment \
-                         variable is set.",
-                        res.status(),
-                        url
-                    ));
-                } else {
-                    return Err(format!(
-                        "API call received invalid status ({}) for : {}",
-                        res.status(),
-                        url
-                    ));
-                }
-            }
+    ) -> Result<T, String> {
+        trace!("URL: {}", url);
+
+        let res = client
+            .request(method, url)
+            .headers(headers.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+
+        debug!("HTTP Status Received: {}", res.status());
+
+        if !res.status().is_success() {
+            let status = res.status();
+            return Err(format!(
+                "API call received invalid status ({}) for : {} ({})",
+                status,
+                url,
+                res.text().unwrap_or_default()
+            ));
+        }
 
-            let has_next = match res.headers().get("x-next-page") {
-                None => {
-                    trace!("No more pages, x-next-page header missing.");
-                    false
-                }
-                Some(n) => {
-                    if n.is_empty() {
-                        trace!("No more pages, x-next-page-header empty.");
-                        false
-                    } else {
-                        trace!("Next page: {:?}", n);
-                        true
-                    }
-                }
-            };
+        parse_json(res)
+    }
+
+    /// Id of the group at `path`, missing subgroups of the configured group are created
+    fn group_id(&self, path: &str, client: &Client, headers: &HeaderMap) -> Result<u64, String> {
+        let url = format!("{}/api/v4/groups/{}", self.url, encode_path(path));
+        trace!("URL: {}", url);
 
-            let results_page: Vec<T> = serde_json::from_reader(res)
-                .map_err(|e| format!("Unable to parse response as JSON ({e})"))?;
+        let res = client
+            .get(&url)
+            .headers(headers.clone())
+            .send()
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
 
-            results.extend(results_page);
+        debug!("HTTP Status Received: {}", res.status());
 
-            if !has_next {
-                break;
+        match res.status() {
+            StatusCode::OK => {
+                let group: Group = parse_json(res)?;
+                Ok(group.id)
             }
+            StatusCode::NOT_FOUND if path != self.group => {
+                let (parent, name) = path.rsplit_once('/').unwrap_or((&self.group, path));
+                let parent_id = self.group_id(parent, client, headers)?;
+                let body = serde_json::json!({
+                    "name": name,
+                    "path": name,
+                    "parent_id": parent_id,
+                });
+                let url = format!("{}/api/v4/groups", self.url);
+                let group: Group = self.send(Method::POST, &url, &body, client, headers)?;
+                info!("Created group {}", path);
+                Ok(group.id)
+            }
+            status => Err(format!(
+                "API call received invalid status ({status}) for : {url}"
+            )),
         }
-        Ok(results)
+    }
+
+    fn get_paged<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> Result<Vec<T>, String> {
+        get_paged(
+            &format!("{url}?per_page={PER_PAGE}&page=1"),
+            |page_url| get(page_url, client, headers),
+            |res, _| {
+                let next = match res.headers().get("x-next-page") {
+                    None => {
+                        trace!("No more pages, x-next-page header missing.");
+                        None
+                    }
+                    Some(n) if n.is_empty() => {
+                        trace!("No more pages, x-next-page-header empty.");
+                        None
+                    }
+                    Some(n) => {
+                        trace!("Next page: {:?}", n);
+                        let page = n.to_str().map_err(|e| format!("Invalid next page ({e})"))?;
+                        Some(format!("{url}?per_page={PER_PAGE}&page={page}"))
+                    }
+                };
+                Ok((parse_json(res)?, next))
+            },
+        )
     }
 
     fn get_projects(
@@ -148,19 +216,7 @@ impl Provider for GitLab {
 
         let use_http = self.use_http;
 
-        let mut headers = HeaderMap::new();
-        if let Some(ref token) = self.private_token {
-            match HeaderValue::from_str(token) {
-                Ok(token) => {
-                    headers.insert("PRIVATE-TOKEN", token);
-                }
-                Err(err) => {
-                    error!("Unable to parse PRIVATE_TOKEN: {}", err);
-                }
-            }
-        } else {
-            warn!("PRIVATE_TOKEN not set")
-        }
+        let headers = self.headers();
 
         let groups = if self.recursive {
             self.get_subgroups(&self.group, &client, &headers).or_else(
@@ -184,21 +240,28 @@ impl Provider for GitLab {
         for p in projects {
             match serde_yaml::from_str::<Desc>(&p.description) {
                 Ok(desc) => {
-                    if desc.skip {
-                        mirrors.push(Err(MirrorError::Skip(p.web_url)));
-                        continue;
-                    }
-                    trace!("{0} -> {1}", desc.origin, p.ssh_url_to_repo);
                     let destination = if use_http {
                         p.http_url_to_repo
                     } else {
                         p.ssh_url_to_repo
                     };
+                    if desc.skip || (desc.archived && p.archived) {
+                        mirrors.push(Err(MirrorError::Skip(
+                            p.web_url,
+                            Some(desc.origin),
+                            Some(destination),
+                        )));
+                        continue;
+                    }
+                    trace!("{0} -> {1}", desc.origin, destination);
                     let m = Mirror {
                         origin: desc.origin,
                         destination,
                         refspec: desc.refspec,
                         lfs: desc.lfs,
+                        topics: desc.topics,
+                        archived: desc.archived,
+                        max_destructive_changes: desc.max_destructive_changes,
                     };
                     mirrors.push(Ok(m));
                 }
@@ -210,4 +273,63 @@ impl Provider for GitLab {
 
         Ok(mirrors)
     }
+
+    fn create_project(&self, project: &NewProject) -> Result<String, String> {
+        let client = Client::new();
+        let headers = self.headers();
+
+        let namespace = match project.namespace {
+            Some(ref n) => format!("{}/{}", self.group, n.trim_matches('/')),
+            None => self.group.clone(),
+        };
+        let namespace_id = self.group_id(&namespace, &client, &headers)?;
+
+        let body = serde_json::json!({
+            "name": project.name,
+            "path": project.name,
+            "namespace_id": namespace_id,
+            "visibility": project.visibility.as_str(),
+            "description": project.description(),
+        });
+        let url = format!("{}/api/v4/projects", self.url);
+        let created: CreatedProject = self.send(Method::POST, &url, &body, &client, &headers)?;
+        Ok(created.web_url)
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    fn update_metadata(&self, mirror: &Mirror, metadata: &Metadata) -> Result<(), String> {
+        let client = Client::new();
+        let headers = self.headers();
+        let url = format!(
+            "{}/api/v4/projects/{}",
+            self.url,
+            encode_path(&project_path(&mirror.destination))
+        );
+
+        let mut body = serde_json::Map::new();
+        if let Some(ref branch) = metadata.default_branch {
+            body.insert("default_branch".to_string(), branch.as_str().into());
+        }
+        if let Some(ref topics) = metadata.topics {
+            body.insert("topics".to_string(), topics.clone().into());
+        }
+        if !body.is_empty() {
+            self.send::<serde_json::Value>(Method::PUT, &url, &body.into(), &client, &headers)?;
+        }
+
+        if metadata.archived {
+            let url = format!("{url}/archive");
+            self.send::<serde_json::Value>(
+                Method::POST,
+                &url,
+                &serde_json::json!({}),
+                &client,
+                &headers,
+            )?;
+        }
+        Ok(())
+    }
 }
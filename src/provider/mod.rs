@@ -4,14 +4,33 @@
  * SPDX-License-Identifier:     MIT
  */
 
+use std::collections::BTreeMap;
+
+// Used for error and debug logging
+use log::{debug, trace};
+
+// Used for API access via HTTPS
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
 use thiserror::Error;
+
+use crate::guard::Threshold;
+
 /// A representation of a mirror job from origin to destination
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mirror {
     pub origin: String,
     pub destination: String,
     pub refspec: Option<Vec<String>>,
     pub lfs: bool,
+    /// Topics to set on the destination project
+    pub topics: Option<Vec<String>>,
+    /// Archive the destination project, making it read-only
+    pub archived: bool,
+    /// Overrides the global limit on refs deleted or rewritten by a push
+    pub max_destructive_changes: Option<Threshold>,
 }
 
 /// An error occuring during mirror creation
@@ -19,8 +38,12 @@ pub struct Mirror {
 pub enum MirrorError {
     #[error("data store disconnected")]
     Description(String, serde_yaml::Error),
+    /// The URL of the project and the error of the provider getting its mirror
+    #[error("provider error")]
+    Provider(String, String),
+    /// The URL of the skipped project, its origin and its destination, if known
     #[error("entry explicitly skipped")]
-    Skip(String),
+    Skip(String, Option<String>, Option<String>),
 }
 
 #[inline]
@@ -28,6 +51,18 @@ pub fn bool_true() -> bool {
     true
 }
 
+/// Parse a manifest file in the format selected by its extension, YAML for unknown extensions
+pub(crate) fn parse_manifest<T: serde::de::DeserializeOwned>(
+    content: &str,
+    extension: Option<&str>,
+) -> Result<T, String> {
+    match extension {
+        Some("toml") => toml::from_str(content).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(content).map_err(|e| e.to_string()),
+        _ => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+    }
+}
+
 pub type MirrorResult = Result<Mirror, MirrorError>;
 
 /// A structured description
@@ -39,15 +74,173 @@ struct Desc {
     refspec: Option<Vec<String>>,
     #[serde(default = "bool_true")]
     lfs: bool,
+    topics: Option<Vec<String>>,
+    #[serde(default)]
+    archived: bool,
+    max_destructive_changes: Option<Threshold>,
+}
+
+/// Visibility of a created project
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Private,
+    Internal,
+    Public,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Internal => "internal",
+            Visibility::Public => "public",
+        }
+    }
+}
+
+/// A destination project to create for mirroring `origin`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewProject {
+    pub origin: String,
+    pub name: String,
+    /// Path below the group, `None` to create the project in the group itself
+    pub namespace: Option<String>,
+    pub visibility: Visibility,
+}
+
+impl NewProject {
+    /// The project description making git-mirror sync the project from `origin`
+    pub fn description(&self) -> String {
+        let desc = BTreeMap::from([("origin", &self.origin)]);
+        let desc = serde_yaml::to_string(&desc).unwrap_or_default();
+        desc.trim_end().to_string()
+    }
+}
+
+/// Metadata of a destination project, updated after a successful sync
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Name of the branch `HEAD` of the origin points to
+    pub default_branch: Option<String>,
+    pub topics: Option<Vec<String>>,
+    /// Archive the project, unarchiving isn't done automatically
+    pub archived: bool,
 }
 
 pub trait Provider {
     fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String>;
     fn get_label(&self) -> String;
+
+    /// Create the project `project` with a description pointing to its origin,
+    /// returns the URL of the created project
+    fn create_project(&self, project: &NewProject) -> Result<String, String> {
+        Err(format!(
+            "Creating {} isn't supported by {}",
+            project.name,
+            self.get_label()
+        ))
+    }
+
+    /// Whether the provider implements `update_metadata`
+    fn supports_metadata(&self) -> bool {
+        false
+    }
+
+    /// Update the metadata of the destination project of `mirror`.
+    ///
+    /// An archived project is read-only, it was synced a last time before archiving.
+    /// `get_mirror_repos` skips it if its description asks for archiving.
+    fn update_metadata(&self, mirror: &Mirror, _metadata: &Metadata) -> Result<(), String> {
+        Err(format!(
+            "Updating the metadata of {} isn't supported by {}",
+            mirror.destination,
+            self.get_label()
+        ))
+    }
+}
+
+/// Path of the project at `url`, e.g. `group/project` for
+/// `git@example.org:group/project.git` or `https://example.org/group/project.git`
+fn project_path(url: &str) -> String {
+    let (_, path) = crate::layout::split_origin(url);
+    let path = path.trim_matches('/');
+    path.strip_suffix(".git").unwrap_or(path).to_string()
+}
+
+/// Fail on any status but `200 OK`, pointing to the private token if the call was unauthorized
+fn check_status(res: Response, url: &str) -> Result<Response, String> {
+    match res.status() {
+        StatusCode::OK => Ok(res),
+        // Azure DevOps redirects unauthenticated requests to a sign in page
+        StatusCode::UNAUTHORIZED | StatusCode::NON_AUTHORITATIVE_INFORMATION => Err(format!(
+            "API call received unauthorized ({}) for: {}. \
+             Please make sure the `PRIVATE_TOKEN` environment \
+             variable is set.",
+            res.status(),
+            url
+        )),
+        status => Err(format!(
+            "API call received invalid status ({status}) for : {url}"
+        )),
+    }
+}
+
+/// Send a GET request to `url`, failing on any status but `200 OK`
+fn get(url: &str, client: &Client, headers: &HeaderMap) -> Result<Response, String> {
+    trace!("URL: {}", url);
+
+    let res = client
+        .get(url)
+        .headers(headers.clone())
+        .send()
+        .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+
+    debug!("HTTP Status Received: {}", res.status());
+
+    check_status(res, url)
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(res: Response) -> Result<T, String> {
+    serde_json::from_reader(res).map_err(|e| format!("Unable to parse response as JSON ({e})"))
+}
+
+/// Get all pages of a listing starting at `url`.
+///
+/// `get` requests a page, `read_page` parses it into its items and the URL of the next page.
+/// It gets the number of items received so far.
+fn get_paged<T>(
+    url: &str,
+    mut get: impl FnMut(&str) -> Result<Response, String>,
+    mut read_page: impl FnMut(Response, usize) -> Result<(Vec<T>, Option<String>), String>,
+) -> Result<Vec<T>, String> {
+    let mut results: Vec<T> = Vec::new();
+    let mut next = Some(url.to_string());
+
+    while let Some(url) = next {
+        let res = get(&url)?;
+        let (page, next_url) = read_page(res, results.len())?;
+        results.extend(page);
+        next = next_url;
+    }
+    Ok(results)
 }
 
 mod gitlab;
 pub use self::gitlab::GitLab;
 
 mod github;
-pub use self::github::GitHub;
+pub use self::github::{GitHub, GitHubOwner};
+
+mod gitea;
+pub use self::gitea::Gitea;
+
+mod bitbucket_server;
+pub use self::bitbucket_server::BitbucketServer;
+
+mod azure_devops;
+pub use self::azure_devops::AzureDevOps;
+
+mod file;
+pub use self::file::File;
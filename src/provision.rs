@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::Path;
+
+use crate::layout;
+use crate::provider::{parse_manifest, NewProject, Visibility};
+
+/// A project to create in the manifest, unset fields are taken from the template
+#[derive(Deserialize, Debug)]
+struct Entry {
+    origin: String,
+    name: Option<String>,
+    namespace: Option<String>,
+    visibility: Option<Visibility>,
+}
+
+/// The provisioning manifest, either a plain list or a template with a `projects` list
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Manifest {
+    List(Vec<Entry>),
+    Template {
+        #[serde(default)]
+        visibility: Visibility,
+        namespace: Option<String>,
+        projects: Vec<Entry>,
+    },
+}
+
+impl Manifest {
+    fn projects(self) -> Vec<NewProject> {
+        let (visibility, namespace, entries) = match self {
+            Manifest::List(entries) => (Visibility::default(), None, entries),
+            Manifest::Template {
+                visibility,
+                namespace,
+                projects,
+            } => (visibility, namespace, projects),
+        };
+        entries
+            .into_iter()
+            .map(|e| {
+                let (host, path) = layout::split_origin(&e.origin);
+                let path = path.trim_matches('/');
+                let path = path.strip_suffix(".git").unwrap_or(path);
+                let (owner, repo) = path.rsplit_once('/').unwrap_or(("", path));
+                let namespace = e
+                    .namespace
+                    .or_else(|| namespace.clone())
+                    .map(|n| {
+                        n.replace("{host}", host)
+                            .replace("{owner}", owner)
+                            .trim_matches('/')
+                            .to_string()
+                    })
+                    .filter(|n| !n.is_empty());
+                NewProject {
+                    name: e.name.unwrap_or_else(|| repo.to_string()),
+                    namespace,
+                    visibility: e.visibility.unwrap_or(visibility),
+                    origin: e.origin,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Load the projects to create from the manifest at `path`.
+///
+/// The template's `namespace` can use `{host}` and `{owner}`,
+/// which are replaced by the host and the path without the repository name of the origin.
+pub fn load(path: &Path) -> Result<Vec<NewProject>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read manifest: {path:?} ({e})"))?;
+
+    let extension = path.extension().and_then(|e| e.to_str());
+    let manifest = parse_manifest::<Manifest>(&content, extension)
+        .map_err(|e| format!("Unable to parse manifest: {path:?} ({e})"))?;
+
+    Ok(manifest.projects())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_manifest, Manifest};
+    use crate::provider::{NewProject, Visibility};
+
+    #[test]
+    fn apply_template() {
+        let manifest = parse_manifest::<Manifest>(
+            r#"
+visibility: internal
+namespace: "{host}/{owner}"
+projects:
+  - origin: https://github.com/rust-lang/cargo.git
+  - origin: git@gitlab.com:a/b/c
+    name: c-mirror
+    namespace: ""
+    visibility: public
+"#,
+            Some("yml"),
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.projects(),
+            [
+                NewProject {
+                    origin: "https://github.com/rust-lang/cargo.git".to_string(),
+                    name: "cargo".to_string(),
+                    namespace: Some("github.com/rust-lang".to_string()),
+                    visibility: Visibility::Internal,
+                },
+                NewProject {
+                    origin: "git@gitlab.com:a/b/c".to_string(),
+                    name: "c-mirror".to_string(),
+                    namespace: None,
+                    visibility: Visibility::Public,
+                },
+            ]
+        );
+
+        let list =
+            parse_manifest::<Manifest>(r#"[{"origin": "https://example.org/x"}]"#, Some("json"));
+        let projects = list.unwrap().projects();
+        assert_eq!(projects[0].name, "x");
+        assert_eq!(projects[0].namespace, None);
+        assert_eq!(projects[0].visibility, Visibility::Private);
+        assert_eq!(projects[0].description(), "origin: https://example.org/x");
+    }
+}
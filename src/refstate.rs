@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// Used for error and debug logging
+use log::{debug, warn};
+
+// File inside the working repository recording the last pushed refs
+const REF_STATE_FILE: &str = "git-mirror-refs.json";
+
+/// The refs of the origin at the time of the last successful push to a destination
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RefState {
+    pub refspec: Option<Vec<String>>,
+    pub lfs: bool,
+    /// Ref name to object id as reported by `git ls-remote`
+    pub refs: BTreeMap<String, String>,
+}
+
+/// Last pushed ref state per destination.
+/// The same origin can be mirrored to multiple destinations using the same working repository.
+type RefStates = BTreeMap<String, RefState>;
+
+fn load(repo_dir: &Path) -> RefStates {
+    let path = repo_dir.join(REF_STATE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Ignoring invalid ref state {:?} ({})", path, e);
+            RefStates::new()
+        }),
+        Err(_) => RefStates::new(),
+    }
+}
+
+/// Check if the state of the last push to `destination` matches `state`
+pub fn is_unchanged(repo_dir: &Path, destination: &str, state: &RefState) -> bool {
+    let unchanged = load(repo_dir).get(destination) == Some(state);
+    debug!("Ref state for {} unchanged: {}", destination, unchanged);
+    unchanged
+}
+
+/// Record the state of a successful push to `destination`.
+///
+/// The caller holds the lock of the working repository, the other destinations are kept.
+pub fn store(repo_dir: &Path, destination: &str, state: RefState) -> Result<(), String> {
+    let path = repo_dir.join(REF_STATE_FILE);
+    let mut states = load(repo_dir);
+    states.insert(destination.to_string(), state);
+    let content = serde_json::to_string_pretty(&states)
+        .map_err(|e| format!("Unable to serialize ref state ({e})"))?;
+    // Write and rename, so an interrupted write doesn't lose the states of the other destinations
+    let tmp = repo_dir.join(format!("{REF_STATE_FILE}.tmp"));
+    fs::write(&tmp, content).map_err(|e| format!("Unable to write ref state {tmp:?} ({e})"))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Unable to replace ref state {path:?} ({e})"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_unchanged, store, RefState, REF_STATE_FILE};
+
+    fn state(main: &str) -> RefState {
+        RefState {
+            refspec: None,
+            lfs: false,
+            refs: [("refs/heads/main".to_string(), main.to_string())].into(),
+        }
+    }
+
+    #[test]
+    fn store_per_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path();
+        assert!(!is_unchanged(repo_dir, "d1", &state("1")));
+
+        store(repo_dir, "d1", state("1")).unwrap();
+        store(repo_dir, "d2", state("2")).unwrap();
+        assert!(is_unchanged(repo_dir, "d1", &state("1")));
+        assert!(is_unchanged(repo_dir, "d2", &state("2")));
+        assert!(!is_unchanged(repo_dir, "d1", &state("2")));
+        assert!(!is_unchanged(
+            repo_dir,
+            "d1",
+            &RefState {
+                lfs: true,
+                ..state("1")
+            }
+        ));
+
+        store(repo_dir, "d1", state("3")).unwrap();
+        assert!(is_unchanged(repo_dir, "d1", &state("3")));
+        assert!(is_unchanged(repo_dir, "d2", &state("2")));
+        assert!(!repo_dir.join(format!("{REF_STATE_FILE}.tmp")).exists());
+    }
+
+    #[test]
+    fn invalid_state_is_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_dir = dir.path();
+        std::fs::write(repo_dir.join(REF_STATE_FILE), "{").unwrap();
+        assert!(!is_unchanged(repo_dir, "d1", &state("1")));
+
+        store(repo_dir, "d1", state("1")).unwrap();
+        assert!(is_unchanged(repo_dir, "d1", &state("1")));
+    }
+}
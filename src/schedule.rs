@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+// Time handling
+use time::OffsetDateTime;
+
+/// When to run the sync in daemon mode
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Start a new sync the given time after the start of the previous one
+    Interval(Duration),
+    /// Start a sync at the times given by a cron expression (UTC)
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Parse a cron expression.
+    ///
+    /// Both the classic five field format (`min hour day month weekday`)
+    /// and the extended format with seconds and an optional year are accepted.
+    pub fn cron(expression: &str) -> Result<Schedule, String> {
+        let expression = expression.trim();
+        let expression = if expression.split_whitespace().count() == 5 {
+            format!("0 {expression}")
+        } else {
+            expression.to_string()
+        };
+        cron::Schedule::from_str(&expression)
+            .map(|s| Schedule::Cron(Box::new(s)))
+            .map_err(|e| format!("Invalid cron expression: {expression} ({e})"))
+    }
+
+    /// Whether a sync should be started right away when the daemon starts
+    pub fn run_on_start(&self) -> bool {
+        matches!(self, Schedule::Interval(_))
+    }
+
+    /// Get the start time of the next sync
+    pub fn next_run(&self, last_start: OffsetDateTime, now: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            Schedule::Interval(interval) => last_start + *interval,
+            Schedule::Cron(schedule) => {
+                let now = DateTime::<Utc>::from_timestamp(now.unix_timestamp(), 0)
+                    .unwrap_or_else(Utc::now);
+                schedule
+                    .after(&now)
+                    .next()
+                    .and_then(|n| OffsetDateTime::from_unix_timestamp(n.timestamp()).ok())
+                    .unwrap_or(OffsetDateTime::now_utc() + Duration::from_secs(60 * 60))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use std::time::Duration;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn next_run() {
+        // 2026-01-01 10:00:00 UTC
+        let start = OffsetDateTime::from_unix_timestamp(1767261600).unwrap();
+        let now = start + Duration::from_secs(450);
+
+        let interval = Schedule::Interval(Duration::from_secs(600));
+        assert!(interval.run_on_start());
+        assert_eq!(
+            interval.next_run(start, now),
+            start + Duration::from_secs(600)
+        );
+
+        let cron = Schedule::cron("*/15 * * * *").unwrap();
+        assert!(!cron.run_on_start());
+        assert_eq!(cron.next_run(start, now), start + Duration::from_secs(900));
+
+        assert!(Schedule::cron("0 */15 * * * *").is_ok());
+        assert!(Schedule::cron("every hour").is_err());
+    }
+}
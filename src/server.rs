@@ -0,0 +1,85 @@
+/*
+ * Copyright (c) 2026 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+// Used for error and debug logging
+use log::{debug, error, info};
+
+// Monitoring
+use prometheus::{Encoder, TextEncoder};
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::error::{GitMirrorError, Result};
+
+// Set once the first sync run has finished
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Mark the instance as ready, e.g. after the first sync run
+pub fn set_ready() {
+    READY.store(true, Ordering::Relaxed);
+}
+
+/// Start a HTTP server in the background exposing
+/// - `/metrics` the gathered prometheus metrics
+/// - `/healthz` always OK while the process is running
+/// - `/readyz` OK once the first sync run has finished
+pub fn start(addr: &str) -> Result<()> {
+    let server = Server::http(addr)
+        .map_err(|e| GitMirrorError::GenericError(format!("Unable to listen on: {addr} ({e})")))?;
+    info!("Listening on http://{}", server.server_addr());
+
+    thread::Builder::new()
+        .name("http".to_string())
+        .spawn(move || {
+            for request in server.incoming_requests() {
+                handle(request);
+            }
+        })
+        .map_err(|e| GitMirrorError::GenericError(format!("Unable to start HTTP server ({e})")))?;
+
+    Ok(())
+}
+
+fn handle(request: Request) {
+    debug!("HTTP request: {} {}", request.method(), request.url());
+
+    let response = match (request.method(), request.url()) {
+        (Method::Get, "/metrics") => metrics(),
+        (Method::Get, "/healthz") => Response::from_string("OK"),
+        (Method::Get, "/readyz") => {
+            if READY.load(Ordering::Relaxed) {
+                Response::from_string("OK")
+            } else {
+                Response::from_string("Waiting for first sync run").with_status_code(503)
+            }
+        }
+        (Method::Get, _) => Response::from_string("Not Found").with_status_code(404),
+        _ => Response::from_string("Method Not Allowed").with_status_code(405),
+    };
+
+    if let Err(e) = request.respond(response) {
+        error!("Unable to send HTTP response ({})", e);
+    }
+}
+
+fn metrics() -> Response<std::io::Cursor<Vec<u8>>> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(_) => {
+            let content_type = Header::from_bytes("Content-Type", encoder.format_type())
+                .expect("Invalid content type");
+            Response::from_data(buffer).with_header(content_type)
+        }
+        Err(e) => {
+            error!("Unable to encode metrics ({})", e);
+            Response::from_string("Unable to encode metrics").with_status_code(500)
+        }
+    }
+}
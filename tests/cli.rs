@@ -19,3 +19,15 @@ fn version_flag_working() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn status_without_group() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+
+    let mirror_dir = tempfile::tempdir()?;
+    cmd.arg("status").arg("--mirror-dir").arg(mirror_dir.path());
+
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    Ok(())
+}
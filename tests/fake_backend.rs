@@ -0,0 +1,560 @@
+// GitError keeps the failed command for the error message
+#![allow(clippy::result_large_err)]
+
+use git_mirror::error::GitMirrorError;
+use git_mirror::git::{GitError, GitWrapper};
+use git_mirror::ledger::{Ledger, Refs};
+use git_mirror::lock::LockMode;
+use git_mirror::provider::{
+    Metadata, Mirror, MirrorError, MirrorResult, NewProject, Provider, Visibility,
+};
+use git_mirror::{do_mirror, provision, prune, restore, MirrorOptions};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// In-memory git backend, repositories are the refs stored by url or path
+#[derive(Clone, Default)]
+struct FakeGit {
+    repos: Arc<Mutex<HashMap<String, Refs>>>,
+    /// Number of clones timing out after creating the repository directory
+    clone_timeouts: Arc<Mutex<u32>>,
+    /// Number of pushes timing out
+    push_timeouts: Arc<Mutex<u32>>,
+    /// Number of `git_fetch` calls
+    fetches: Arc<Mutex<u32>>,
+}
+
+impl FakeGit {
+    fn set(&self, url: &str, refs: &[(&str, &str)]) {
+        let refs = refs
+            .iter()
+            .map(|(n, id)| (n.to_string(), id.to_string()))
+            .collect();
+        self.repos.lock().unwrap().insert(url.to_string(), refs);
+    }
+
+    fn get(&self, url: &str) -> Result<Refs, GitError> {
+        self.repos
+            .lock()
+            .unwrap()
+            .get(url)
+            .cloned()
+            .ok_or_else(|| GitError::GitCommandError {
+                code: 128,
+                stderr: "remote: Repository not found.".to_string(),
+                cmd: Command::new("git"),
+            })
+    }
+
+    fn fetch(&self, origin: &str, repo_dir: &Path) -> Result<(), GitError> {
+        let refs = self.get(origin)?;
+        // The mirror directory decides between clone and update, it looks like a bare repository
+        fs::create_dir_all(repo_dir.join("objects")).unwrap();
+        fs::write(repo_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        self.repos
+            .lock()
+            .unwrap()
+            .insert(repo_dir.to_string_lossy().to_string(), refs);
+        Ok(())
+    }
+}
+
+impl GitWrapper for FakeGit {
+    fn git_version(&self) -> Result<(), GitError> {
+        Ok(())
+    }
+
+    fn git_lfs_version(&self) -> Result<(), GitError> {
+        Ok(())
+    }
+
+    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, _lfs: bool) -> Result<(), GitError> {
+        if repo_dir.exists() {
+            return Err(GitError::GitCommandError {
+                code: 128,
+                stderr: "fatal: destination path already exists".to_string(),
+                cmd: Command::new("git"),
+            });
+        }
+        let mut timeouts = self.clone_timeouts.lock().unwrap();
+        if *timeouts > 0 {
+            *timeouts -= 1;
+            fs::create_dir_all(repo_dir.join("objects")).unwrap();
+            return Err(GitError::Timeout {
+                operation: "clone".to_string(),
+                timeout: Duration::from_secs(1),
+            });
+        }
+        self.fetch(origin, repo_dir)
+    }
+
+    fn git_update_mirror(&self, origin: &str, repo_dir: &Path, _lfs: bool) -> Result<(), GitError> {
+        self.fetch(origin, repo_dir)
+    }
+
+    fn git_push_mirror(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        refspec: &Option<Vec<String>>,
+        _lfs: bool,
+    ) -> Result<(), GitError> {
+        let mut timeouts = self.push_timeouts.lock().unwrap();
+        if *timeouts > 0 {
+            *timeouts -= 1;
+            return Err(GitError::Timeout {
+                operation: "push".to_string(),
+                timeout: Duration::from_secs(1),
+            });
+        }
+        drop(timeouts);
+        let refs = self.get(&repo_dir.to_string_lossy())?;
+        let mut repos = self.repos.lock().unwrap();
+        match refspec {
+            // Only full `src:dst` refspecs are supported
+            Some(specs) => {
+                let dest = repos.entry(dest.to_string()).or_default();
+                for spec in specs {
+                    let (src, dst) = spec.trim_start_matches('+').split_once(':').unwrap();
+                    dest.insert(dst.to_string(), refs[src].clone());
+                }
+            }
+            // Like on forges, pull request refs are hidden from the push and kept
+            None => {
+                let hidden: Refs = repos
+                    .get(dest)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(n, _)| n.starts_with("refs/pull/"))
+                    .map(|(n, id)| (n.clone(), id.clone()))
+                    .collect();
+                repos.insert(dest.to_string(), refs.into_iter().chain(hidden).collect());
+            }
+        }
+        Ok(())
+    }
+
+    fn git_ls_remote(&self, url: &str) -> Result<Vec<(String, String)>, GitError> {
+        Ok(self.get(url)?.into_iter().collect())
+    }
+
+    fn git_default_branch(&self, url: &str) -> Result<Option<String>, GitError> {
+        self.get(url)?;
+        Ok(Some("refs/heads/main".to_string()))
+    }
+
+    fn git_fetch(&self, repo_dir: &Path, url: &str, refspecs: &[String]) -> Result<(), GitError> {
+        *self.fetches.lock().unwrap() += 1;
+        let remote = self.get(url)?;
+        fs::create_dir_all(repo_dir).unwrap();
+        let mut repos = self.repos.lock().unwrap();
+        let local = repos
+            .entry(repo_dir.to_string_lossy().to_string())
+            .or_default();
+        for spec in refspecs {
+            let (src, dst) = spec.trim_start_matches('+').split_once(':').unwrap();
+            local.insert(dst.to_string(), remote[src].clone());
+        }
+        Ok(())
+    }
+
+    /// Object ids are numbers in a linear history
+    fn git_is_ancestor(
+        &self,
+        _repo_dir: &Path,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitError> {
+        Ok(ancestor.parse::<u32>().unwrap() <= descendant.parse::<u32>().unwrap())
+    }
+}
+
+#[derive(Default)]
+struct FakeProvider {
+    /// Label of the provider, `fake` if not set
+    label: Option<&'static str>,
+    mirrors: Vec<(&'static str, &'static str)>,
+    /// Origins and destinations of skipped entries
+    skipped: Vec<(&'static str, &'static str)>,
+    /// Whether the mirrors are archived
+    archived: bool,
+    /// Metadata updates by destination
+    updated: Arc<Mutex<Vec<(String, Metadata)>>>,
+    /// Created projects
+    created: Arc<Mutex<Vec<NewProject>>>,
+}
+
+impl Provider for FakeProvider {
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let mirrors = self.mirrors.iter().map(|(origin, destination)| {
+            Ok(Mirror {
+                origin: origin.to_string(),
+                destination: destination.to_string(),
+                refspec: None,
+                lfs: false,
+                topics: Some(vec!["mirror".to_string()]),
+                archived: self.archived,
+                max_destructive_changes: None,
+            })
+        });
+        let skipped = self.skipped.iter().map(|(o, d)| {
+            Err(MirrorError::Skip(
+                d.to_string(),
+                Some(o.to_string()),
+                Some(d.to_string()),
+            ))
+        });
+        Ok(mirrors.chain(skipped).collect())
+    }
+
+    fn get_label(&self) -> String {
+        self.label.unwrap_or("fake").to_string()
+    }
+
+    fn create_project(&self, project: &NewProject) -> Result<String, String> {
+        if project.name == "forbidden" {
+            return Err("403 Forbidden".to_string());
+        }
+        self.created.lock().unwrap().push(project.clone());
+        Ok(format!("dest/{}", project.name))
+    }
+
+    fn supports_metadata(&self) -> bool {
+        true
+    }
+
+    fn update_metadata(&self, mirror: &Mirror, metadata: &Metadata) -> Result<(), String> {
+        self.updated
+            .lock()
+            .unwrap()
+            .push((mirror.destination.clone(), metadata.clone()));
+        Ok(())
+    }
+}
+
+fn providers() -> Vec<Box<dyn Provider>> {
+    vec![Box::new(FakeProvider {
+        mirrors: vec![
+            ("origin/a", "dest/a"),
+            ("origin/b", "dest/b"),
+            ("origin/missing", "dest/missing"),
+        ],
+        ..Default::default()
+    })]
+}
+
+fn only_a() -> Vec<Box<dyn Provider>> {
+    vec![Box::new(FakeProvider {
+        mirrors: vec![("origin/a", "dest/a")],
+        ..Default::default()
+    })]
+}
+
+/// A fresh mirror directory synced with an empty fake backend
+fn fixture() -> (TempDir, FakeGit, MirrorOptions) {
+    let dir = tempfile::tempdir().unwrap();
+    let git = FakeGit::default();
+    let opts = MirrorOptions {
+        mirror_dir: dir.path().to_path_buf(),
+        worker_count: 2,
+        fail_on_sync_error: true,
+        git: Some(Box::new(git.clone())),
+        ..Default::default()
+    };
+    (dir, git, opts)
+}
+
+#[test]
+fn sync_with_fake_backend() {
+    let (dir, git, opts) = fixture();
+    let mirror_dir = dir.path();
+    git.set("origin/a", &[("HEAD", "1"), ("refs/heads/main", "1")]);
+    git.set(
+        "origin/b",
+        &[("refs/heads/main", "2"), ("refs/tags/v1", "3")],
+    );
+
+    let result = do_mirror(providers(), &opts);
+    assert!(
+        matches!(result, Err(GitMirrorError::SyncError(1))),
+        "{result:?}"
+    );
+    assert_eq!(git.get("dest/a").unwrap(), git.get("origin/a").unwrap());
+    assert_eq!(git.get("dest/b").unwrap(), git.get("origin/b").unwrap());
+
+    let ledger = Ledger::load(mirror_dir).unwrap();
+    assert_eq!(ledger.repos["dest/b"].refs, git.get("origin/b").unwrap());
+    assert_eq!(ledger.repos["dest/missing"].consecutive_failures, 1);
+
+    // Updates of the origin are pushed on the next run
+    git.set("origin/a", &[("HEAD", "4"), ("refs/heads/main", "4")]);
+    let _ = do_mirror(providers(), &opts);
+    assert_eq!(git.get("dest/a").unwrap()["refs/heads/main"], "4");
+
+    let ledger = Ledger::load(mirror_dir).unwrap();
+    assert_eq!(ledger.repos["dest/a"].refs["refs/heads/main"], "4");
+    // HEAD isn't pushed
+    assert!(!ledger.repos["dest/a"].refs.contains_key("HEAD"));
+    assert_eq!(ledger.repos["dest/missing"].consecutive_failures, 2);
+}
+
+#[test]
+fn prune_orphaned_repos() {
+    let (dir, git, mut opts) = fixture();
+    let mirror_dir = dir.path();
+    git.set("origin/a", &[("refs/heads/main", "1")]);
+    git.set("origin/b", &[("refs/heads/main", "2")]);
+    git.set("origin/c", &[("refs/heads/main", "3")]);
+    let _ = do_mirror(providers(), &opts);
+    let other = || -> Vec<Box<dyn Provider>> {
+        vec![Box::new(FakeProvider {
+            label: Some("other"),
+            mirrors: vec![("origin/c", "dest/c")],
+            ..Default::default()
+        })]
+    };
+    do_mirror(other(), &opts).unwrap();
+    let repo_a = opts.layout.repo_dir(mirror_dir, "origin/a");
+    let repo_b = opts.layout.repo_dir(mirror_dir, "origin/b");
+    let repo_c = opts.layout.repo_dir(mirror_dir, "origin/c");
+    assert!(repo_a.is_dir() && repo_b.is_dir() && repo_c.is_dir());
+
+    let untracked = |name: &str| {
+        let repo_dir = mirror_dir.join(name);
+        fs::create_dir_all(repo_dir.join("objects")).unwrap();
+        fs::write(repo_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        repo_dir
+    };
+    let stale = untracked("stale.git");
+    let backup = untracked("git-mirror-backup.git");
+
+    // Skipped entries are still in use, repositories of other groups are left alone
+    let skip_b: Vec<Box<dyn Provider>> = vec![Box::new(FakeProvider {
+        mirrors: vec![("origin/a", "dest/a")],
+        skipped: vec![("origin/b", "dest/b")],
+        ..Default::default()
+    })];
+    prune(skip_b, &opts, None).unwrap();
+    assert!(repo_b.is_dir() && repo_c.is_dir() && backup.is_dir());
+    assert!(!stale.exists());
+
+    // A shared mirror directory is only pruned with all its groups
+    opts.lock_mode = LockMode::Repo;
+    let stale = untracked("stale.git");
+    assert!(prune(only_a(), &opts, None).is_err());
+    assert!(repo_b.is_dir());
+    let mut all = only_a();
+    all.extend(other());
+    prune(all, &opts, None).unwrap();
+    assert!(repo_a.is_dir() && repo_c.is_dir() && stale.is_dir());
+    assert!(!repo_b.exists());
+
+    let ledger = Ledger::load(mirror_dir).unwrap();
+    assert_eq!(
+        ledger.repos.keys().collect::<Vec<_>>(),
+        ["dest/a", "dest/c"]
+    );
+}
+
+#[test]
+fn provision_missing_projects() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("projects.yml");
+    fs::write(
+        &manifest,
+        "visibility: internal\nprojects:\n  - origin: origin/a\n  - origin: origin/b\n  - origin: origin/c\n",
+    )
+    .unwrap();
+    // Skipped and archived projects exist already
+    let provider = FakeProvider {
+        mirrors: vec![("origin/a", "dest/a")],
+        skipped: vec![("origin/c", "dest/c")],
+        ..Default::default()
+    };
+
+    provision(&provider, &manifest, true).unwrap();
+    assert!(provider.created.lock().unwrap().is_empty());
+
+    // Only the project not mirrored yet is created
+    provision(&provider, &manifest, false).unwrap();
+    let created = provider.created.lock().unwrap().clone();
+    assert_eq!(
+        created,
+        [NewProject {
+            origin: "origin/b".to_string(),
+            name: "b".to_string(),
+            namespace: None,
+            visibility: Visibility::Internal,
+        }]
+    );
+    assert_eq!(created[0].description(), "origin: origin/b");
+
+    fs::write(&manifest, "- origin: origin/forbidden\n").unwrap();
+    assert!(provision(&provider, &manifest, false).is_err());
+}
+
+#[test]
+fn remove_timed_out_clone() {
+    let (dir, git, mut opts) = fixture();
+    git.set("origin/a", &[("refs/heads/main", "1")]);
+    opts.retry_delay = Duration::ZERO;
+    let repo_a = opts.layout.repo_dir(dir.path(), "origin/a");
+
+    // The retry clones again instead of updating the partial clone
+    *git.clone_timeouts.lock().unwrap() = 1;
+    do_mirror(only_a(), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap(), git.get("origin/a").unwrap());
+    assert_eq!(*git.clone_timeouts.lock().unwrap(), 0);
+
+    fs::remove_dir_all(&repo_a).unwrap();
+    *git.clone_timeouts.lock().unwrap() = 3;
+    let result = do_mirror(only_a(), &opts);
+    assert!(
+        matches!(result, Err(GitMirrorError::SyncError(1))),
+        "{result:?}"
+    );
+    assert!(!repo_a.exists());
+}
+
+#[test]
+fn sync_metadata_after_push() {
+    let (_dir, git, mut opts) = fixture();
+    git.set("origin/a", &[("refs/heads/main", "1")]);
+    opts.sync_metadata = true;
+    opts.skip_unchanged = true;
+
+    let updated = Arc::new(Mutex::new(Vec::new()));
+    let provider = |archived| -> Vec<Box<dyn Provider>> {
+        vec![Box::new(FakeProvider {
+            mirrors: vec![("origin/a", "dest/a"), ("origin/missing", "dest/missing")],
+            updated: updated.clone(),
+            archived,
+            ..Default::default()
+        })]
+    };
+    let _ = do_mirror(provider(false), &opts);
+    assert_eq!(
+        *updated.lock().unwrap(),
+        [(
+            "dest/a".to_string(),
+            Metadata {
+                default_branch: Some("main".to_string()),
+                topics: Some(vec!["mirror".to_string()]),
+                archived: false,
+            }
+        )]
+    );
+
+    // Nothing changed, nothing is updated
+    let _ = do_mirror(provider(false), &opts);
+    assert_eq!(updated.lock().unwrap().len(), 1);
+
+    // The description changed, but the refs didn't
+    let _ = do_mirror(provider(true), &opts);
+    let updated = updated.lock().unwrap();
+    assert_eq!(updated.len(), 2);
+    assert!(updated[1].1.archived);
+}
+
+#[test]
+fn refuse_destructive_push() {
+    let (dir, git, mut opts) = fixture();
+    let mirror_dir = dir.path();
+    let refs = [
+        ("refs/heads/main", "2"),
+        ("refs/heads/dev", "3"),
+        ("refs/tags/v1", "1"),
+    ];
+    git.set("origin/a", &refs);
+    git.set("dest/a", &[]);
+    opts.max_destructive_changes = Some("1".parse().unwrap());
+    do_mirror(only_a(), &opts).unwrap();
+
+    // Fast-forwards and a single deletion are fine
+    git.set(
+        "origin/a",
+        &[("refs/heads/main", "4"), ("refs/tags/v1", "1")],
+    );
+    do_mirror(only_a(), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap(), git.get("origin/a").unwrap());
+
+    // The tag moved and main was rewritten
+    git.set(
+        "origin/a",
+        &[("refs/heads/main", "3"), ("refs/tags/v1", "5")],
+    );
+    opts.dry_run = true;
+    let result = do_mirror(only_a(), &opts);
+    assert!(
+        matches!(result, Err(GitMirrorError::DestructiveChange(_))),
+        "{result:?}"
+    );
+
+    // Refused pushes fail the run even if sync errors are tolerated
+    opts.dry_run = false;
+    opts.fail_on_sync_error = false;
+    let code: i32 = do_mirror(only_a(), &opts).unwrap_err().into();
+    assert_eq!(code, 5);
+    assert_eq!(git.get("dest/a").unwrap()["refs/tags/v1"], "1");
+    let ledger = Ledger::load(mirror_dir).unwrap();
+    let error = ledger.repos["dest/a"].error.clone().unwrap();
+    assert!(error.starts_with("Refusing destructive push"), "{error}");
+
+    opts.max_destructive_changes = Some("100%".parse().unwrap());
+    opts.dry_run = true;
+    do_mirror(only_a(), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap()["refs/tags/v1"], "1");
+    opts.dry_run = false;
+    do_mirror(only_a(), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap()["refs/tags/v1"], "5");
+}
+
+#[test]
+fn backup_and_restore_refs() {
+    let (dir, git, mut opts) = fixture();
+    let mirror_dir = dir.path();
+    git.set(
+        "origin/a",
+        &[("refs/heads/main", "1"), ("refs/tags/v1", "1")],
+    );
+    // The pull request ref can't be changed by the push and isn't backed up
+    git.set("dest/a", &[("refs/pull/1/head", "1")]);
+    opts.backup_refs = true;
+    opts.backup_remote = Some("backup".to_string());
+    do_mirror(only_a(), &opts).unwrap();
+    let backup_repo = mirror_dir.join("git-mirror-backup.git");
+    assert!(git.get(&backup_repo.to_string_lossy()).is_err());
+
+    // Upstream moved the tag, the fast-forward of main isn't backed up.
+    // The first push to the backup remote is retried.
+    git.set(
+        "origin/a",
+        &[("refs/heads/main", "2"), ("refs/tags/v1", "3")],
+    );
+    opts.retry_delay = Duration::ZERO;
+    *git.push_timeouts.lock().unwrap() = 1;
+    do_mirror(only_a(), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap()["refs/tags/v1"], "3");
+    assert_eq!(git.get("dest/a").unwrap()["refs/pull/1/head"], "1");
+
+    let backups = git.get(&backup_repo.to_string_lossy()).unwrap();
+    assert_eq!(backups.len(), 1);
+    let (backup, id) = backups.iter().next().unwrap();
+    assert!(backup.starts_with("refs/git-mirror/backup/"), "{backup}");
+    assert!(backup.contains("/dest-a-"), "{backup}");
+    assert!(backup.ends_with("/tags/v1"), "{backup}");
+    assert_eq!(id, "1");
+    assert_eq!(git.get("backup").unwrap(), backups);
+    assert_eq!(*git.fetches.lock().unwrap(), 1);
+
+    let timestamp = backup.split('/').nth(3).unwrap();
+    restore("dest/a", Some(timestamp), &opts).unwrap();
+    assert_eq!(git.get("dest/a").unwrap()["refs/tags/v1"], "1");
+    assert_eq!(git.get("dest/a").unwrap()["refs/heads/main"], "2");
+    assert!(restore("dest/a", Some("19700101T000000Z"), &opts).is_err());
+}